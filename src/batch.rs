@@ -0,0 +1,255 @@
+use chomp::FileId;
+use indent::IndentDiagnostic;
+use lex::{LexError, Lexer};
+use options::LexerOptions;
+use source::{is_literate_name, SourceMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use stream::TokenStream;
+
+// Lexes a lot of files at once, spread over a number of threads. Each thread takes the next file nobody has started
+//   yet, so one huge file doesn't hold up the rest of its share. Results always come back in the order the files
+//   went in, and each file is lexed exactly as it would be on its own, so what you get doesn't depend on how many
+//   threads there were or which one got to which file.
+
+pub struct LexedFile {
+    // Stamped on every span. The file's place in the input, or its id in the SourceMap.
+    pub id: FileId,
+    pub tokens: Result<TokenStream, LexError>,
+    // Indentation problems found before any error.
+    pub diagnostics: Vec<IndentDiagnostic>,
+    // The errors lexing got past, if the options say to recover.
+    pub errors: Vec<LexError>,
+}
+
+pub struct BatchLexer {
+    threads: usize,
+    options: LexerOptions,
+}
+
+impl BatchLexer {
+    // One thread per core.
+    pub fn new() -> BatchLexer {
+        BatchLexer {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            options: LexerOptions::default(),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // 1 lexes everything on the calling thread.
+    pub fn set_threads(&mut self, threads: usize) {
+        assert!(threads > 0, "A BatchLexer needs at least one thread.");
+        self.threads = threads;
+    }
+
+    // For every file. Whether a file is literate goes by its name, though, except in lex_sources where there isn't one.
+    pub fn set_options(&mut self, options: &LexerOptions) {
+        self.options = *options;
+    }
+
+    pub fn lex_sources<S: AsRef<str> + Sync>(&self, sources: &[S]) -> Vec<LexedFile> {
+        self.map(sources, |i, source| {
+            lex_file(FileId(i as u32), source.as_ref().into(), &self.options)
+        })
+    }
+
+    // Reading the files happens on the worker threads too. .litcoffee files are lexed as literate.
+    pub fn lex_paths<P: AsRef<Path> + Sync>(&self, paths: &[P]) -> Vec<io::Result<LexedFile>> {
+        self.map(paths, |i, path| {
+            let path = path.as_ref();
+            let text = fs::read_to_string(path)?;
+            let options = LexerOptions {
+                literate: is_literate_name(&path.to_string_lossy()),
+                ..self.options
+            };
+            Ok(lex_file(FileId(i as u32), text.into(), &options))
+        })
+    }
+
+    // Every file in map, in order, with the ids map gave them.
+    pub fn lex_source_map(&self, map: &SourceMap) -> Vec<LexedFile> {
+        self.map(map.files(), |_, file| {
+            let options = LexerOptions {
+                literate: file.is_literate(),
+                ..self.options
+            };
+            lex_file(file.id(), file.text().into(), &options)
+        })
+    }
+
+    // f on every item, over self.threads threads, with the results in item order.
+    fn map<T, R, F>(&self, items: &[T], f: F) -> Vec<R>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(usize, &T) -> R + Sync,
+    {
+        if self.threads == 1 || items.len() < 2 {
+            return items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| f(i, item))
+                .collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(items.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= items.len() {
+                        break;
+                    }
+                    let result = f(i, &items[i]);
+                    results.lock().unwrap()[i] = Some(result);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+}
+
+impl Default for BatchLexer {
+    fn default() -> BatchLexer {
+        BatchLexer::new()
+    }
+}
+
+fn lex_file(id: FileId, source: Arc<str>, options: &LexerOptions) -> LexedFile {
+    let mut lexer = Lexer::with_options(&source, options);
+    lexer.set_file(id);
+    let tokens = lexer.try_lex();
+    let diagnostics = lexer.diagnostics().to_vec();
+    LexedFile {
+        id,
+        tokens: tokens.map(|tokens| TokenStream::from_tokens(source.clone(), tokens)),
+        diagnostics,
+        errors: lexer.errors().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BatchLexer;
+    use chomp::FileId;
+    use lex::TokenTag::Error;
+    use lex::{LexErrorKind, Lexer};
+    use options::LexerOptions;
+    use source::SourceMap;
+    use std::env;
+    use std::fs;
+
+    fn sources() -> Vec<String> {
+        (0..200)
+            .map(|i| match i % 4 {
+                0 => format!("a{} + \"#{{b}} {}\"\n  c\n", i, "x".repeat(i * 50)),
+                1 => format!("###\n{}\n###\nd{}", "herecomment ".repeat(i), i),
+                2 => format!("if x\n\tyes{}\n        no\n", i),
+                _ => format!("bad{} ? no", i),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_give_the_same_results_in_the_same_order_on_any_number_of_threads() {
+        let sources = sources();
+        let mut batch = BatchLexer::new();
+        batch.set_threads(1);
+        let one = batch.lex_sources(&sources);
+        for threads in [2, 3, 8].iter() {
+            batch.set_threads(*threads);
+            let many = batch.lex_sources(&sources);
+            assert_eq!(many.len(), one.len());
+            for (m, o) in many.iter().zip(one.iter()) {
+                assert_eq!(m.id, o.id);
+                assert_eq!(m.diagnostics, o.diagnostics);
+                match (&m.tokens, &o.tokens) {
+                    (Ok(m), Ok(o)) => assert_eq!(m.tokens(), o.tokens()),
+                    (Err(m), Err(o)) => assert_eq!(m, o),
+                    _ => panic!("Threads changed whether file {:?} lexed.", m.id),
+                }
+            }
+        }
+
+        // And the same as lexing each one by itself.
+        for (i, (file, source)) in one.iter().zip(sources.iter()).enumerate() {
+            assert_eq!(file.id, FileId(i as u32));
+            match Lexer::new(source).try_lex() {
+                Ok(tokens) => {
+                    let stream = file.tokens.as_ref().unwrap();
+                    assert_eq!(stream.source(), source);
+                    assert_eq!(stream.len(), tokens.len());
+                    assert!(stream.tokens().iter().all(|t| t.span.file == file.id));
+                }
+                Err(e) => assert_eq!(file.tokens.as_ref().unwrap_err().kind, e.kind),
+            }
+        }
+        assert_eq!(
+            one[3].tokens.as_ref().unwrap_err().kind,
+            LexErrorKind::UnexpectedChar('?')
+        );
+        assert_eq!(one[2].diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn should_lex_every_file_with_the_options_given() {
+        let mut batch = BatchLexer::new();
+        batch.set_options(&LexerOptions {
+            recover: true,
+            ..LexerOptions::default()
+        });
+        let files = batch.lex_sources(&["a ? b", "c"]);
+        assert_eq!(files[0].tokens.as_ref().unwrap()[2].tag, Error);
+        assert_eq!(files[0].errors[0].kind, LexErrorKind::UnexpectedChar('?'));
+        assert!(files[1].errors.is_empty());
+    }
+
+    #[test]
+    fn should_lex_a_source_map_with_its_ids() {
+        let mut map = SourceMap::new();
+        map.add("a.coffee", "a + 1".to_string());
+        let b = map.add("b.litcoffee", "Prose.\n\n    b".to_string());
+        let mut batch = BatchLexer::new();
+        batch.set_threads(2);
+        let files = batch.lex_source_map(&map);
+        assert_eq!(files[1].id, b);
+        let b_tokens = files[1].tokens.as_ref().unwrap();
+        assert_eq!(b_tokens.text(0), "[Comment Prose.]");
+        assert_eq!(b_tokens[0].span.file, b);
+    }
+
+    #[test]
+    fn should_read_paths_on_the_workers_and_report_missing_files() {
+        let dir = env::temp_dir().join(format!("awarecs-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.coffee");
+        let b = dir.join("b.litcoffee");
+        fs::write(&a, "x + 1").unwrap();
+        fs::write(&b, "Says x.\n\n    x").unwrap();
+        let paths = vec![a, dir.join("missing.coffee"), b];
+
+        let files = BatchLexer::new().lex_paths(&paths);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].as_ref().unwrap().tokens.as_ref().unwrap().len(), 5);
+        assert!(files[1].is_err());
+        let b = files[2].as_ref().unwrap();
+        assert_eq!(b.id, FileId(2));
+        assert_eq!(b.tokens.as_ref().unwrap().text(0), "[Comment Says x.]");
+    }
+}
@@ -0,0 +1,15 @@
+extern crate awarecs;
+
+use std::env;
+use std::io;
+use std::process;
+
+// See awarecs::cli::USAGE, or run awarecs help.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let stderr = io::stderr();
+    let status = awarecs::cli::run(&args, stdin.lock(), &mut stdout.lock(), &mut stderr.lock());
+    process::exit(status);
+}
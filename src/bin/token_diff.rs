@@ -0,0 +1,54 @@
+extern crate awarecs;
+
+use awarecs::diff::{diff_tokens, unescape};
+use awarecs::Lexer;
+use std::env;
+use std::fs;
+use std::process;
+
+// Usage: token_diff <source.coffee> <expected-tokens>
+//
+// The expected file holds one "[Tag text]" per line, with \n, \r, \t and \\ escaped (the same way the report prints
+//   them). Blank lines are ignored. Exits 1 if the streams differ, 2 if something couldn't be read or lexed.
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <source.coffee> <expected-tokens>", args[0]);
+        process::exit(2);
+    }
+
+    let source = read(&args[1]);
+    let expected_text = read(&args[2]);
+    let expected_lines: Vec<String> = expected_text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(unescape)
+        .collect();
+    let expected: Vec<&str> = expected_lines.iter().map(|l| l.as_str()).collect();
+
+    let code = source.as_str();
+    let tokens = match Lexer::new(code).try_lex() {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("could not lex {}: {}", args[1], e);
+            process::exit(2);
+        }
+    };
+    let diff = diff_tokens(&expected, &tokens, &code);
+
+    println!("{}", diff);
+    if !diff.is_match() {
+        process::exit(1);
+    }
+}
+
+fn read(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("could not read {}: {}", path, e);
+            process::exit(2);
+        }
+    }
+}
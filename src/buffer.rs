@@ -0,0 +1,172 @@
+use chomp::{FileId, Span};
+use lex::{LexError, Lexer, Token, TokenTag};
+use line_index::LineIndex;
+use std::mem;
+use std::ops::Range;
+
+// A compact way to keep a lot of tokens around (the project indexer holds on to every token of every file). A Token
+//   is two whole Positions plus a tag and a file id, 64 bytes on a 64 bit machine. Here each token is a u8 tag and
+//   two u32 byte offsets in parallel arrays, 9 bytes, and line and col are worked out again from a LineIndex only
+//   when someone asks for them.
+//
+// Offsets are u32, so sources over 4 GiB won't fit. LexLimits::default() stops well short of that anyway.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenBuffer {
+    tags: Vec<u8>,
+    starts: Vec<u32>,
+    ends: Vec<u32>,
+    // Every token in a buffer comes from the same file.
+    file: FileId,
+}
+
+impl TokenBuffer {
+    pub fn new(file: FileId) -> TokenBuffer {
+        TokenBuffer {
+            tags: vec![],
+            starts: vec![],
+            ends: vec![],
+            file,
+        }
+    }
+
+    // Lexes straight into a buffer, without a Vec<Token> in between.
+    pub fn lex(code: &str) -> Result<TokenBuffer, LexError> {
+        let mut buffer = TokenBuffer::new(FileId::ANONYMOUS);
+        for token in Lexer::new(code) {
+            buffer.push(&token?);
+        }
+        buffer.shrink_to_fit();
+        Ok(buffer)
+    }
+
+    pub fn from_tokens(tokens: &[Token]) -> TokenBuffer {
+        let mut buffer =
+            TokenBuffer::new(tokens.first().map_or(FileId::ANONYMOUS, |t| t.span.file));
+        buffer.tags.reserve_exact(tokens.len());
+        buffer.starts.reserve_exact(tokens.len());
+        buffer.ends.reserve_exact(tokens.len());
+        for token in tokens {
+            buffer.push(token);
+        }
+        buffer
+    }
+
+    pub fn push(&mut self, token: &Token) {
+        assert_eq!(
+            token.span.file, self.file,
+            "Every token in a TokenBuffer has to come from the same file."
+        );
+        self.tags.push(token.tag.to_u8());
+        self.starts.push(offset(token.span.start_pos.index));
+        self.ends.push(offset(token.span.end_pos.index));
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.tags.shrink_to_fit();
+        self.starts.shrink_to_fit();
+        self.ends.shrink_to_fit();
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn tag(&self, i: usize) -> TokenTag {
+        TokenTag::from_u8(self.tags[i]).unwrap()
+    }
+
+    pub fn range(&self, i: usize) -> Range<usize> {
+        self.starts[i] as usize..self.ends[i] as usize
+    }
+
+    // The full Token, the same as the lexer gave. index has to be over the same code the tokens came from.
+    pub fn token(&self, i: usize, index: &LineIndex) -> Token {
+        let range = self.range(i);
+        let start_pos = index.position(range.start);
+        Token::make(
+            self.tag(i),
+            Span::new(start_pos, index.position(range.end), self.file),
+        )
+    }
+
+    pub fn tokens<'b>(&'b self, index: &'b LineIndex) -> impl Iterator<Item = Token> + 'b {
+        (0..self.len()).map(move |i| self.token(i, index))
+    }
+
+    // Heap bytes held, counting spare capacity.
+    pub fn heap_size(&self) -> usize {
+        self.tags.capacity() * mem::size_of::<u8>()
+            + (self.starts.capacity() + self.ends.capacity()) * mem::size_of::<u32>()
+    }
+}
+
+fn offset(index: usize) -> u32 {
+    assert!(
+        index <= u32::MAX as usize,
+        "TokenBuffer can't hold offsets past 4 GiB."
+    );
+    index as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::TokenBuffer;
+    use lex::{Lexer, Token, TokenTag};
+    use line_index::LineIndex;
+    use std::mem;
+
+    #[test]
+    fn every_tag_should_survive_the_trip_through_u8() {
+        for (i, tag) in TokenTag::ALL.iter().enumerate() {
+            assert_eq!(tag.to_u8() as usize, i);
+            assert_eq!(TokenTag::from_u8(tag.to_u8()), Some(*tag));
+        }
+        assert_eq!(TokenTag::from_u8(TokenTag::ALL.len() as u8), None);
+        let custom = TokenTag::Custom(7);
+        assert_eq!(TokenTag::from_u8(custom.to_u8()), Some(custom));
+    }
+
+    #[test]
+    fn should_give_back_exactly_the_tokens_it_was_given() {
+        let code =
+            "\u{feff}#!/usr/bin/env coffee\r\na + \"é #{b}\"\n  ### c\n ###\r\t/d/g + ///e\n///";
+        let tokens = Lexer::new(code).lex();
+        let buffer = TokenBuffer::lex(code).unwrap();
+        assert_eq!(buffer, TokenBuffer::from_tokens(&tokens));
+
+        let index = LineIndex::new(code);
+        assert_eq!(buffer.tokens(&index).collect::<Vec<_>>(), tokens);
+        assert_eq!(&code[buffer.range(1)], "\r\n");
+        assert_eq!(buffer.tag(0), TokenTag::Shebang);
+    }
+
+    #[test]
+    fn should_take_a_fraction_of_the_memory_of_a_token_vec() {
+        let mut code = String::new();
+        for i in 0..5000 {
+            code.push_str(&format!("line{} + \"s #{{x}}\" # c\n  y\n", i));
+        }
+        let tokens = Lexer::new(&code).lex();
+        let buffer = TokenBuffer::from_tokens(&tokens);
+
+        let vec_per_token =
+            (tokens.capacity() * mem::size_of::<Token>()) as f64 / tokens.len() as f64;
+        let buffer_per_token = buffer.heap_size() as f64 / buffer.len() as f64;
+        println!(
+            "{} tokens: Vec<Token> {:.1} bytes/token, TokenBuffer {:.1} bytes/token",
+            tokens.len(),
+            vec_per_token,
+            buffer_per_token
+        );
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(mem::size_of::<Token>(), 64);
+        }
+        assert_eq!(buffer_per_token, 9.0);
+        assert!(vec_per_token >= 5.0 * buffer_per_token);
+    }
+}
@@ -1,6 +1,8 @@
-pub use std::iter::Enumerate;
-use std::ops::Add;
-pub use std::str::Chars;
+use reader_source::ReaderSource;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::ops::{Add, Range};
 
 #[derive(Debug, PartialEq)]
 pub struct ChompResult {
@@ -49,6 +51,7 @@ impl Add for ChompResult {
             span: Span {
                 start_pos: self.span.start_pos,
                 end_pos: rhs.span.end_pos,
+                file: self.span.file,
             },
             hit_eof: rhs.hit_eof,
         }
@@ -75,17 +78,193 @@ impl Add<Option<ChompResult>> for ChompResult {
 //     }
 // }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+// Positions order by index alone; line and col always agree with it.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub struct Position {
     pub index: usize,
     pub line_no: usize,
     pub col_no: usize,
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+impl Position {
+    // Where lexing starts: the top of the file, or just past a byte order mark if there is one. The BOM isn't part
+    //   of any token and doesn't count as a column.
+    pub fn start_of(code: &str) -> Position {
+        Position {
+            index: if code.starts_with('\u{feff}') { 3 } else { 0 },
+            line_no: 1,
+            col_no: 0,
+        }
+    }
+}
+
+// How many bytes of line break text starts with: 2 for \r\n, 1 for a \n or a lone \r, 0 if it doesn't start with one.
+//   The chomper hands all three to the lexer as a single '\n'.
+pub fn newline_len(text: &str) -> usize {
+    if text.starts_with("\r\n") {
+        2
+    } else if text.starts_with('\n') || text.starts_with('\r') {
+        1
+    } else {
+        0
+    }
+}
+
+// The position of byte offset target, counting forward from a known position the same way the chomper does. The
+//   chomper never stops between the \r and \n of a \r\n, but an edit can; there the \r counts as a column.
+pub fn position_after(code: &str, from: Position, target: usize) -> Position {
+    let mut pos = from;
+    while pos.index < target {
+        let rest = &code[pos.index..];
+        let newline = newline_len(rest);
+        if newline > 0 && pos.index + newline <= target {
+            pos.line_no += 1;
+            pos.col_no = 0;
+            pos.index += newline;
+        } else {
+            pos.col_no += 1;
+            pos.index += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    pos
+}
+
+// Which file a Span points into; an index into a SourceMap. Code lexed straight from a &str has no file, and its
+//   spans say FileId::ANONYMOUS.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone)]
+pub struct FileId(pub u32);
+
+impl FileId {
+    pub const ANONYMOUS: FileId = FileId(u32::MAX);
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct Span {
     pub start_pos: Position,
     pub end_pos: Position,
+    pub file: FileId,
+}
+
+// Unlike adding ChompResults, none of this needs the spans to be next to each other. Spans from different files
+//   never contain or intersect each other, and covering them is a bug.
+impl Span {
+    pub fn new(start_pos: Position, end_pos: Position, file: FileId) -> Span {
+        Span {
+            start_pos,
+            end_pos,
+            file,
+        }
+    }
+
+    // An empty span sitting at pos.
+    pub fn empty_at(pos: Position, file: FileId) -> Span {
+        Span::new(pos, pos, file)
+    }
+
+    // The span of a byte range in code, with line and col counted from the top.
+    pub fn from_range(code: &str, range: Range<usize>, file: FileId) -> Span {
+        let start = position_after(code, Position::start_of(code), range.start);
+        Span::new(start, position_after(code, start, range.end), file)
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start_pos.index..self.end_pos.index
+    }
+
+    // In bytes.
+    pub fn len(&self) -> usize {
+        self.end_pos.index - self.start_pos.index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The smallest span that has both of these in it, along with whatever is between them.
+    pub fn cover(&self, other: &Span) -> Span {
+        assert_eq!(
+            self.file, other.file,
+            "Can't cover spans from two different files."
+        );
+        Span::new(
+            self.start_pos.min(other.start_pos),
+            self.end_pos.max(other.end_pos),
+            self.file,
+        )
+    }
+
+    pub fn contains(&self, other: &Span) -> bool {
+        self.file == other.file
+            && self.start_pos <= other.start_pos
+            && other.end_pos <= self.end_pos
+    }
+
+    pub fn contains_index(&self, index: usize) -> bool {
+        self.start_pos.index <= index && index < self.end_pos.index
+    }
+
+    // Whether the two share at least one byte. An empty span doesn't intersect anything.
+    pub fn intersects(&self, other: &Span) -> bool {
+        self.intersection(other).is_some()
+    }
+
+    pub fn intersection(&self, other: &Span) -> Option<Span> {
+        let start = self.start_pos.max(other.start_pos);
+        let end = self.end_pos.min(other.end_pos);
+        if self.file != other.file || start >= end {
+            return None;
+        }
+        Some(Span::new(start, end, self.file))
+    }
+}
+
+// By file, then by where they start, then by where they end (so a span sorts before the longer ones it starts).
+impl Ord for Span {
+    fn cmp(&self, other: &Span) -> Ordering {
+        (self.file, self.start_pos, self.end_pos).cmp(&(other.file, other.start_pos, other.end_pos))
+    }
+}
+
+impl PartialOrd for Span {
+    fn partial_cmp(&self, other: &Span) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// line:col-line:col, with the same 1-based lines and 0-based cols as Position.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.start_pos.line_no,
+            self.start_pos.col_no,
+            self.end_pos.line_no,
+            self.end_pos.col_no
+        )
+    }
+}
+
+impl From<Span> for Range<usize> {
+    fn from(span: Span) -> Range<usize> {
+        span.range()
+    }
+}
+
+impl From<ChompResult> for Span {
+    fn from(cr: ChompResult) -> Span {
+        cr.span
+    }
+}
+
+// A span that was chomped without hitting eof.
+impl From<Span> for ChompResult {
+    fn from(span: Span) -> ChompResult {
+        ChompResult {
+            span,
+            hit_eof: false,
+        }
+    }
 }
 
 pub trait ToSpan {
@@ -104,25 +283,175 @@ impl ToSpan for ChompResult {
     }
 }
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Checkpoint {
+    pub position: Position,
+    pub is_eof: bool,
+}
+
+// How many chars are in some bytes of UTF-8 (which don't start or end part way through one).
+fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count()
+}
+
+// Where a Chomper's text comes from: a &str that's all there at once, or a ReaderSource that reads it a chunk at a
+//   time (see the reader_source module). Offsets are always bytes into the whole input, so the spans come out the
+//   same either way. A source only has to keep what's been read since the last forget_before.
+pub trait CharSource {
+    // The text from byte offset index to as far as has been read.
+    fn text_from(&self, index: usize) -> &str;
+    // The text on hand from the last forget_before up to byte offset index.
+    fn text_before(&self, index: usize) -> &str;
+    // Reads until text_from(index) is at least bytes long, or the input runs out.
+    fn fill(&mut self, index: usize, bytes: usize);
+    // Nothing before byte offset index will be asked for again.
+    fn forget_before(&mut self, index: usize);
+    // How many bytes of input have been read. All of them, for a &str.
+    fn len_read(&self) -> usize;
+    // Why the input ended early, if reading it failed. Until then it looks like the end of the input.
+    fn read_error(&self) -> Option<&io::Error>;
+    // All of the input, if the source has it all at once.
+    fn whole(&self) -> Option<&str>;
+}
+
+impl CharSource for &str {
+    fn text_from(&self, index: usize) -> &str {
+        &self[index..]
+    }
+
+    fn text_before(&self, index: usize) -> &str {
+        &self[..index]
+    }
+
+    fn fill(&mut self, _: usize, _: usize) {}
+
+    fn forget_before(&mut self, _: usize) {}
+
+    fn len_read(&self) -> usize {
+        self.len()
+    }
+
+    fn read_error(&self) -> Option<&io::Error> {
+        None
+    }
+
+    fn whole(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+// What a Chomper reads from. An enum, not a trait object or a type parameter, so the usual &str case takes no
+//   allocation and each call is a match instead of a virtual call, and Lexer (which rules name as a plain type)
+//   doesn't have to be generic.
+pub(crate) enum Source<'c> {
+    Str(&'c str),
+    Reader(ReaderSource<'c>),
+}
+
+impl<'c> CharSource for Source<'c> {
+    fn text_from(&self, index: usize) -> &str {
+        match *self {
+            Source::Str(ref code) => code.text_from(index),
+            Source::Reader(ref reader) => reader.text_from(index),
+        }
+    }
+
+    fn text_before(&self, index: usize) -> &str {
+        match *self {
+            Source::Str(ref code) => code.text_before(index),
+            Source::Reader(ref reader) => reader.text_before(index),
+        }
+    }
+
+    fn fill(&mut self, index: usize, bytes: usize) {
+        match *self {
+            Source::Str(ref mut code) => code.fill(index, bytes),
+            Source::Reader(ref mut reader) => reader.fill(index, bytes),
+        }
+    }
+
+    fn forget_before(&mut self, index: usize) {
+        match *self {
+            Source::Str(ref mut code) => code.forget_before(index),
+            Source::Reader(ref mut reader) => reader.forget_before(index),
+        }
+    }
+
+    fn len_read(&self) -> usize {
+        match *self {
+            Source::Str(ref code) => code.len_read(),
+            Source::Reader(ref reader) => reader.len_read(),
+        }
+    }
+
+    fn read_error(&self) -> Option<&io::Error> {
+        match *self {
+            Source::Str(ref code) => code.read_error(),
+            Source::Reader(ref reader) => reader.read_error(),
+        }
+    }
+
+    fn whole(&self) -> Option<&str> {
+        match *self {
+            Source::Str(ref code) => code.whole(),
+            Source::Reader(ref reader) => reader.whole(),
+        }
+    }
+}
+
+// How many bytes past the chomper are always on hand (unless the input ends first). Everything that looks ahead
+//   without chomping (peek, text, looking_at, the str quit fns) sees at least this much; rest_of_line sees further.
+pub const LOOKAHEAD: usize = 64;
+
 pub struct Chomper<'chomper> {
-    pub code: &'chomper str,
+    source: Source<'chomper>,
+    // Where the code starts: 0, or 3 past a BOM.
+    start: usize,
+    // A byte offset into the input, always on a char boundary. Spans are sliced straight out of the source with it.
     pub index: usize,
-    char_iterator: Enumerate<Chars<'chomper>>,
     pub is_eof: bool,
     pub line_no: usize,
     pub col_no: usize,
+    // Stamped on every span we hand out.
+    pub file: FileId,
 }
 
 impl<'ci> Chomper<'ci> {
     pub fn new(code: &'ci str) -> Chomper<'ci> {
         // don't forget, line numbers start at 1!!!!
+        Chomper::starting_at(code, Position::start_of(code))
+    }
+
+    // Picks up somewhere in the middle of code, as if everything before position had already been chomped.
+    pub fn starting_at(code: &'ci str, position: Position) -> Chomper<'ci> {
         Chomper {
-            code: code,
-            index: 0,
-            char_iterator: code.chars().enumerate(),
+            source: Source::Str(code),
+            start: Position::start_of(code).index,
+            index: position.index,
+            is_eof: false,
+            line_no: position.line_no,
+            col_no: position.col_no,
+            file: FileId::ANONYMOUS,
+        }
+    }
+
+    // From the top of what reader reads, past a BOM if it starts with one.
+    pub fn with_reader(reader: ReaderSource<'ci>) -> Chomper<'ci> {
+        let mut source = Source::Reader(reader);
+        source.fill(0, LOOKAHEAD);
+        let start = if source.text_from(0).starts_with('\u{feff}') {
+            3
+        } else {
+            0
+        };
+        Chomper {
+            source,
+            start,
+            index: start,
             is_eof: false,
             line_no: 1,
             col_no: 0,
+            file: FileId::ANONYMOUS,
         }
     }
 
@@ -134,48 +463,132 @@ impl<'ci> Chomper<'ci> {
         }
     }
 
+    // Somewhere to come back to, when what's coming up might not turn out to be what we hoped.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            position: self.position(),
+            is_eof: self.is_eof,
+        }
+    }
+
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.index = checkpoint.position.index;
+        self.line_no = checkpoint.position.line_no;
+        self.col_no = checkpoint.position.col_no;
+        self.is_eof = checkpoint.is_eof;
+    }
+
+    // Where the code starts: the top of the input, or just past its BOM.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    // All of the code, if the chomper has it all at once (it was made from a &str).
+    pub fn code(&self) -> Option<&str> {
+        self.source.whole()
+    }
+
+    // Nothing before byte offset index will be looked at again, so a streaming source can let go of it. Checkpoints
+    //   and spans before it can't be rewound to or have their value taken after this.
+    pub fn forget_before(&mut self, index: usize) {
+        self.source.forget_before(index.min(self.index));
+    }
+
+    // How many bytes of input have been read so far.
+    pub fn len_read(&self) -> usize {
+        self.source.len_read()
+    }
+
+    // Why the input ended early, if it did. The chomper hits eof there, same as at the real end.
+    pub fn read_error(&self) -> Option<&io::Error> {
+        self.source.read_error()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn source(&self) -> &Source<'ci> {
+        &self.source
+    }
+
+    fn keep_lookahead(&mut self) {
+        if self.source.text_from(self.index).len() < LOOKAHEAD {
+            self.source.fill(self.index, LOOKAHEAD);
+        }
+    }
+
     fn assert_not_eof(&self) {
         if self.is_eof {
             panic!("Chomper is at EOF.");
         }
     }
 
+    // A \r\n or a lone \r comes back as '\n', so nothing past the chomper has to care about line endings.
     pub fn peek(&self) -> Option<char> {
-        let target = self.index;
-        if target >= self.code.len() {
-            return None;
-        };
-        // Some(self.code.char_at(target))
-        Some(self.code.chars().nth(target).unwrap()) // something about unicode may make this invalid, but that's a challenge for another day
+        match self.text().chars().next() {
+            Some('\r') => Some('\n'),
+            c => c,
+        }
     }
 
-    pub fn text(&self) -> &'ci str {
+    // What's coming up: at least LOOKAHEAD bytes of it, or the rest of the input if that's less.
+    pub fn text(&self) -> &str {
         // THIS IS THE PROBLEM RIGHT HERE. THIS PANICS ON THE HEREDOC EOF TEST.
         // self.code.slice_from(self.index)
-        &self.code[self.index..]
+        self.source.text_from(self.index)
     }
 
+    // What's coming up, through to the end of the line, not counting the line break. Reads as far as it takes.
+    pub fn rest_of_line(&mut self) -> &str {
+        let mut wanted = LOOKAHEAD;
+        let end = loop {
+            let text = self.source.text_from(self.index);
+            if let Some(end) = text.find(['\n', '\r']) {
+                break end;
+            }
+            if text.len() < wanted {
+                break text.len();
+            }
+            wanted = text.len() * 2;
+            self.source.fill(self.index, wanted);
+        };
+        &self.source.text_from(self.index)[..end]
+    }
+
+    // The char just before the chomper, if it hasn't been forgotten.
+    pub fn previous_char(&self) -> Option<char> {
+        self.source.text_before(self.index).chars().next_back()
+    }
+
+    // Not an Iterator: it panics at eof instead of ending.
+    #[allow(
+        clippy::should_implement_trait,
+        clippy::assign_op_pattern,
+        clippy::needless_return
+    )]
     pub fn next(&mut self) -> Option<(usize, char)> {
         self.assert_not_eof();
-        let result = self.char_iterator.next();
-        self.index = self.index + 1;
+        let result = self.peek().map(|c| (self.index, c));
 
         match result {
             None => {
                 self.is_eof = true;
             }
             Some((_, '\n')) => {
+                self.index = self.index + newline_len(self.text());
                 self.line_no = self.line_no + 1;
                 self.col_no = 0;
             }
-            _ => self.col_no = self.col_no + 1,
+            Some((_, c)) => {
+                self.index = self.index + c.len_utf8();
+                self.col_no = self.col_no + 1;
+            }
         };
+        self.keep_lookahead();
 
         return result;
     }
 
     pub fn expect(&mut self, expectation: &str) -> ChompResult {
-        if !self.text().starts_with(expectation) {
+        if !self.looking_at(expectation) {
             panic!(
                 "At index {}, expected {} but got \r\n {}.",
                 self.index,
@@ -184,9 +597,27 @@ impl<'ci> Chomper<'ci> {
             )
         }
 
-        self.chomp_count(expectation.len()).unwrap()
+        self.chomp_count(expectation.chars().count()).unwrap()
     }
 
+    // Whether the text coming up is expectation, where a \n in expectation matches any line break.
+    pub fn looking_at(&self, expectation: &str) -> bool {
+        let mut rest = self.text();
+        for e in expectation.chars() {
+            let len = match e {
+                '\n' => newline_len(rest),
+                _ if rest.starts_with(e) => e.len_utf8(),
+                _ => 0,
+            };
+            if len == 0 {
+                return false;
+            }
+            rest = &rest[len..];
+        }
+        true
+    }
+
+    #[allow(clippy::assign_op_pattern)]
     pub fn chomp_count(&mut self, count: usize) -> Option<ChompResult> {
         let mut chomped = 0;
 
@@ -210,6 +641,97 @@ impl<'ci> Chomper<'ci> {
         self.chomp_internal(|_, __| false, quit)
     }
 
+    // The same as chomp_till_str(|str| delimiters.iter().any(|d| str.starts_with(d))), only instead of stepping
+    //   through every char it searches the bytes for the next place a delimiter could start and jumps there. For long
+    //   herecomments and the like.
+    pub fn chomp_till_any_str(&mut self, delimiters: &[&str]) -> Option<ChompResult> {
+        if self.is_eof {
+            return None;
+        }
+        if delimiters.iter().any(|d| d.is_empty()) {
+            return self.chomp_till_str(|_| true);
+        }
+        let firsts: Vec<u8> = delimiters.iter().map(|d| d.as_bytes()[0]).collect();
+        let start_position = self.position();
+        let end_position;
+        loop {
+            let candidate = self.next_candidate(&firsts);
+            self.skip_to(candidate);
+            if self.peek().is_none() {
+                end_position = self.position();
+                self.next();
+                break;
+            }
+            let text = self.text();
+            if delimiters.iter().any(|d| text.starts_with(d)) {
+                end_position = self.position();
+                break;
+            }
+            self.next();
+        }
+
+        if end_position == start_position {
+            return None;
+        }
+        Some(ChompResult {
+            span: Span::new(start_position, end_position, self.file),
+            hit_eof: self.is_eof,
+        })
+    }
+
+    // Everything up to the next line break, fast. The same as chomp(|c| c == '\n').
+    pub fn chomp_line(&mut self) -> Option<ChompResult> {
+        self.chomp_till_any_str(&["\n", "\r"])
+    }
+
+    // The byte offset of the next of these bytes at or after the chomper, or of the end of what's on hand if there
+    //   isn't one there (so go there and ask again). Never the \n of a \r\n, which the chomper steps over as one.
+    pub fn next_candidate(&self, bytes: &[u8]) -> usize {
+        let text = self.text().as_bytes();
+        let mut from = 0;
+        loop {
+            match text[from..].iter().position(|b| bytes.contains(b)) {
+                // Stopping after a \r could be stopping in the middle of a \r\n that hasn't been read yet.
+                None if text.last() == Some(&b'\r') => return self.index + text.len() - 1,
+                None => return self.index + text.len(),
+                Some(i) => {
+                    let at = from + i;
+                    if at > 0 && text[at] == b'\n' && text[at - 1] == b'\r' {
+                        from = at + 1;
+                    } else {
+                        return self.index + at;
+                    }
+                }
+            }
+        }
+    }
+
+    // Moves straight to byte offset target, counting the lines and cols in between in bulk instead of one char at a
+    //   time. target has to be on a char boundary at or after the chomper, and not between the \r and \n of a \r\n.
+    pub fn skip_to(&mut self, target: usize) {
+        let text = self.source.text_from(self.index);
+        let skipped = &text.as_bytes()[..target - self.index];
+        if skipped.is_empty() {
+            return;
+        }
+        assert!(
+            !(skipped[skipped.len() - 1] == b'\r' && text[target - self.index..].starts_with('\n')),
+            "Can't skip to the middle of a \\r\\n."
+        );
+        let is_break = |b: &u8| *b == b'\n' || *b == b'\r';
+        match skipped.iter().rposition(is_break) {
+            None => self.col_no += char_count(skipped),
+            Some(last) => {
+                let crlfs = skipped.windows(2).filter(|w| w == b"\r\n").count();
+                self.line_no =
+                    self.line_no + skipped.iter().filter(|b| is_break(b)).count() - crlfs;
+                self.col_no = char_count(&skipped[last + 1..]);
+            }
+        }
+        self.index = target;
+        self.keep_lookahead();
+    }
+
     pub fn chomp<F>(&mut self, mut quit: F) -> Option<ChompResult>
     where
         F: FnMut(char) -> bool,
@@ -224,6 +746,7 @@ impl<'ci> Chomper<'ci> {
         self.chomp_internal(quit, |_, __| false)
     }
 
+    #[allow(clippy::partialeq_to_none)]
     fn chomp_internal<F, G>(&mut self, mut char_quit: F, mut str_quit: G) -> Option<ChompResult>
     where
         F: FnMut(char, Option<char>) -> bool,
@@ -239,10 +762,6 @@ impl<'ci> Chomper<'ci> {
         let mut start_position: Option<Position> = None;
         let mut end_position: Option<Position> = None;
 
-        println!("starting a chomp at text: {}", self.text());
-        println!("index is: {}", self.index);
-        println!("is_eof is {}", self.is_eof);
-        println!("last valid index of code is {}", self.code.len() - 1);
         // todo I KNOW this can be simplified and cleaned up
         loop {
             let should_quit = match self.peek() {
@@ -258,10 +777,8 @@ impl<'ci> Chomper<'ci> {
                         end_position = Some(self.position());
                         true
                     } else {
-                        println!("Not time to quit yet! Char is: {}", ch);
                         prev_char = Some(ch);
                         if start_position == None {
-                            println!("setting start index for chomp at {}", self.index);
                             start_position = Some(self.position());
                         }
                         self.next();
@@ -271,39 +788,118 @@ impl<'ci> Chomper<'ci> {
             };
 
             if should_quit {
-                println!("Just about to create ChompResult");
-                println!("start_position is: {:?}", start_position);
-                println!("end_position is: {:?}", end_position);
-
                 if start_position == None {
                     return None;
                 }
-                let cr = Some(ChompResult {
+                return Some(ChompResult {
                     span: Span {
                         start_pos: start_position.unwrap(),
                         end_pos: end_position.unwrap(),
+                        file: self.file,
                     },
                     hit_eof: self.is_eof,
                 });
-
-                println!("Full chomp result is: {:?}", cr);
-                return cr;
             }
         }
     }
 
-    pub fn value(&self, chomp_result: &ChompResult) -> &'ci str {
+    // The text of a span the chomper has been over (and hasn't been told to forget).
+    pub fn value<T: ToSpan>(&self, chomped: &T) -> &str {
         // self.code.slice(
         //     chomp_result.span.start_pos.index,
         //     chomp_result.span.end_pos.index,
         // )
-        &self.code[chomp_result.span.start_pos.index..chomp_result.span.end_pos.index]
+        let span = chomped.to_span();
+        &self.source.text_from(span.start_pos.index)[..span.len()]
+    }
+}
+
+// Test fixtures shared with the pattern and lex tests.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{position_after, Chomper, Position};
+
+    // Awkward text for checking the fast paths against the slow ones: every kind of line break (some back to back),
+    //   multibyte chars right next to delimiters, escapes before closers and line breaks, and delimiters cut short
+    //   by eof.
+    pub(crate) const CORPUS: &[&str] = &[
+        "",
+        "\u{feff}### é ###\r\n",
+        "a\r\n\r\n\rb\n\r\n\r",
+        "###é😀\r\n  \t ## #\n###",
+        "\"a\\\"b\\\\\" #{c} \\#{d}\\\r\ne\"",
+        "   \t\u{a0} x\t\r  y  \u{3000}\n",
+        "##",
+        "é\\",
+        "\r",
+        "///a\\///b///gi\n///",
+    ];
+
+    // A chomper at every place in code the lexer could leave one: every char boundary past the BOM, except between
+    //   the \r and \n of a \r\n.
+    pub(crate) fn chompers_everywhere<'c>(code: &'c str) -> Vec<Chomper<'c>> {
+        let start = Position::start_of(code);
+        (start.index..=code.len())
+            .filter(|&i| {
+                code.is_char_boundary(i)
+                    && !(code[..i].ends_with('\r') && code[i..].starts_with('\n'))
+            })
+            .map(|i| Chomper::starting_at(code, position_after(code, start, i)))
+            .collect()
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::is_digit_ascii_radix, clippy::bool_assert_comparison)]
 mod test {
-    use super::{ChompResult, Chomper};
+    use super::fixtures::{chompers_everywhere, CORPUS};
+    use super::{position_after, ChompResult, Chomper, Position};
+
+    fn assert_same_place(fast: &Chomper, slow: &Chomper) {
+        assert_eq!(fast.position(), slow.position());
+        assert_eq!(fast.is_eof, slow.is_eof);
+    }
+
+    #[test]
+    fn skipping_ahead_should_end_up_where_stepping_does() {
+        let delimiters: &[&[&str]] = &[&["###"], &["\"", "#{", "\\"], &["\n"], &["é", "\r\n"]];
+        for code in CORPUS.iter() {
+            for chomper in chompers_everywhere(code) {
+                let start = chomper.position();
+                for ds in delimiters.iter() {
+                    let mut fast = Chomper::starting_at(code, start);
+                    let mut slow = Chomper::starting_at(code, start);
+                    assert_eq!(
+                        fast.chomp_till_any_str(ds),
+                        slow.chomp_till_str(|str| ds.iter().any(|d| str.starts_with(d))),
+                        "{:?} from {} till {:?}",
+                        code,
+                        start.index,
+                        ds
+                    );
+                    assert_same_place(&fast, &slow);
+                }
+
+                let mut fast = Chomper::starting_at(code, start);
+                let mut slow = Chomper::starting_at(code, start);
+                assert_eq!(fast.chomp_line(), slow.chomp(|c| c == '\n'));
+                assert_same_place(&fast, &slow);
+
+                // Skipping straight to anywhere further on should count lines and cols like stepping there would.
+                for target in chompers_everywhere(code) {
+                    let target = target.position();
+                    if target.index >= start.index {
+                        let mut skipper = Chomper::starting_at(code, start);
+                        skipper.skip_to(target.index);
+                        assert_eq!(
+                            skipper.position(),
+                            position_after(code, start, target.index)
+                        );
+                    }
+                }
+            }
+        }
+    }
 
     #[test]
     fn it_should_track_line_and_col_numbers() {
@@ -509,4 +1105,146 @@ chomp it until 42, which is the first digit."#;
         assert_eq!(combined.span.end_pos.index, 6);
         assert_eq!(chomper.is_eof, true);
     }
+
+    #[test]
+    fn index_should_be_a_byte_offset_even_past_multibyte_chars() {
+        let code = "héllo wörld";
+        let mut chomper = Chomper::new(code);
+        let word = chomper.chomp(|c| c == ' ').unwrap();
+        assert_eq!(chomper.value(&word), "héllo");
+        assert_eq!(word.span.end_pos.index, 6);
+        assert_eq!(word.span.end_pos.col_no, 5);
+        assert_eq!(chomper.peek(), Some(' '));
+    }
+
+    #[test]
+    fn starting_at_should_pick_up_mid_stream() {
+        let code = "ab\ncd";
+        let mut chomper = Chomper::starting_at(
+            code,
+            Position {
+                index: 3,
+                line_no: 2,
+                col_no: 0,
+            },
+        );
+        let cr = chomper.chomp(|_| false).unwrap();
+        assert_eq!(chomper.value(&cr), "cd");
+        assert_eq!(cr.span.end_pos.line_no, 2);
+        assert_eq!(cr.span.end_pos.col_no, 2);
+    }
+
+    #[test]
+    fn crlf_and_lone_cr_should_each_be_one_newline() {
+        let code = "a\r\nb\rc\nd";
+        let mut chomper = Chomper::new(code);
+        let mut seen = String::new();
+        while let Some((_, c)) = chomper.next() {
+            seen.push(c);
+        }
+        assert_eq!(seen, "a\nb\nc\nd");
+        assert_eq!(chomper.line_no, 4);
+        assert_eq!(chomper.col_no, 1);
+        assert_eq!(chomper.index, code.len());
+        assert_eq!(
+            super::position_after(code, Position::start_of(code), 3),
+            chomper_at(code, 3)
+        );
+    }
+
+    fn chomper_at(code: &str, index: usize) -> Position {
+        let mut chomper = Chomper::new(code);
+        while chomper.index < index {
+            chomper.next();
+        }
+        chomper.position()
+    }
+
+    #[test]
+    fn expect_should_match_a_newline_against_any_line_break() {
+        let mut chomper = Chomper::new("\r\n\rx");
+        let cr = chomper.expect("\n\n");
+        assert_eq!(cr.span.end_pos.index, 3);
+        assert_eq!(cr.span.end_pos.line_no, 3);
+        assert!(!chomper.looking_at("\n"));
+    }
+
+    #[test]
+    fn a_leading_bom_should_be_skipped() {
+        let code = "\u{feff}ab";
+        let mut chomper = Chomper::new(code);
+        let cr = chomper.chomp(|_| false).unwrap();
+        assert_eq!(chomper.value(&cr), "ab");
+        assert_eq!(cr.span.start_pos.index, 3);
+        assert_eq!(cr.span.end_pos.col_no, 2);
+    }
+
+    fn span_of(code: &str, range: ::std::ops::Range<usize>) -> super::Span {
+        super::Span::from_range(code, range, super::FileId::ANONYMOUS)
+    }
+
+    #[test]
+    fn cover_should_not_need_adjacent_spans() {
+        let code = "ab\ncd ef";
+        let a = span_of(code, 0..1);
+        let e = span_of(code, 6..8);
+        let covered = a.cover(&e);
+        assert_eq!(covered, e.cover(&a));
+        assert_eq!(covered.range(), 0..8);
+        assert_eq!(covered.len(), 8);
+        assert_eq!(covered.to_string(), "1:0-2:5");
+        assert!(covered.contains(&a) && covered.contains(&e));
+        assert!(!a.contains(&covered));
+    }
+
+    #[test]
+    fn intersection_should_be_the_shared_bytes() {
+        let code = "abcdef";
+        let left = span_of(code, 0..4);
+        let right = span_of(code, 2..6);
+        assert_eq!(left.intersection(&right).unwrap().range(), 2..4);
+        assert!(left.intersects(&right));
+        assert!(!left.intersects(&span_of(code, 4..6)));
+        assert!(!left.intersects(&span_of(code, 2..2)));
+        assert!(span_of(code, 2..2).is_empty());
+
+        let mut other_file = right;
+        other_file.file = super::FileId(7);
+        assert!(!left.intersects(&other_file));
+        assert!(!span_of(code, 0..6).contains(&other_file));
+    }
+
+    #[test]
+    fn spans_should_sort_by_file_then_start_then_end() {
+        let code = "abcdef";
+        let mut other_file = span_of(code, 0..1);
+        other_file.file = super::FileId(0);
+        let mut spans = [
+            span_of(code, 2..3),
+            span_of(code, 0..4),
+            span_of(code, 0..2),
+            other_file,
+        ];
+        spans.sort();
+        let ranges: Vec<_> = spans.iter().map(|s| s.range()).collect();
+        assert_eq!(ranges, vec![0..1, 0..2, 0..4, 2..3]);
+        assert_eq!(spans[0].file, super::FileId(0));
+    }
+
+    #[test]
+    fn spans_should_convert_to_and_from_ranges_and_chomp_results() {
+        let code = "ab\r\ncd";
+        let mut chomper = Chomper::new(code);
+        chomper.chomp_count(2);
+        let cr = chomper.chomp(|_| false).unwrap();
+        let span: super::Span = cr.into();
+        assert_eq!(span, span_of(code, 2..6));
+        let range: ::std::ops::Range<usize> = span.into();
+        assert_eq!(range, 2..6);
+        assert_eq!(ChompResult::from(span).span, span);
+        assert_eq!(
+            super::Span::empty_at(span.end_pos, span.file).to_string(),
+            "2:2-2:2"
+        );
+    }
 }
@@ -0,0 +1,427 @@
+use highlight::{to_ansi, to_html, to_html_page, AnsiTheme};
+use lex::{LexError, Lexer};
+use line_index::LineIndex;
+use options::{Dialect, LexerOptions};
+use source::{is_literate_name, Location};
+use std::fs;
+use std::io::{self, Read, Write};
+use token_format::TokenFormat;
+
+// The awarecs command. Everything but main lives here so it can be run against in-memory input and output. The
+//   exit code is 0 when all went well, 1 when lexing found errors (they're written to stderr as
+//   path:line:col: error: ..., with the line and a caret under the spot), and 2 for bad arguments or a file that
+//   couldn't be read.
+
+pub const USAGE: &str = "\
+usage: awarecs <command> [options] [file...]
+
+Reads each file, or stdin if there are none (or for -).
+
+commands:
+  tokens      print the tokens
+  check       report every lexing error and indentation problem, and nothing else
+  highlight   print the code with syntax highlighting (never fails on bad code)
+  help        print this
+
+options:
+  --format <f>  tokens: text (the default), coffee (like coffee --tokens) or json
+                highlight: ansi (the default), html or html-page
+  --literate    lex as Literate CoffeeScript (.litcoffee files always are)
+  --cs1         lex as CoffeeScript 1 (no JSX, 1.x keywords)
+  --recover     keep going after an error, and print the tokens anyway
+";
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Command {
+    Tokens(TokenFormat),
+    Check,
+    Highlight(HighlightFormat),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum HighlightFormat {
+    Ansi,
+    Html,
+    HtmlPage,
+}
+
+struct Args {
+    command: Command,
+    options: LexerOptions,
+    // Lex everything as literate, not just .litcoffee files.
+    literate: bool,
+    files: Vec<String>,
+}
+
+// args doesn't include the program name.
+pub fn run<R, W, E>(args: &[String], stdin: R, stdout: &mut W, stderr: &mut E) -> i32
+where
+    R: Read,
+    W: Write,
+    E: Write,
+{
+    match try_run(args, stdin, stdout, stderr) {
+        Ok(status) => status,
+        // Whatever we were writing to stopped reading (| head, say). Not our problem.
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            let _ = writeln!(stderr, "awarecs: {}", e);
+            2
+        }
+    }
+}
+
+fn try_run<R, W, E>(
+    args: &[String],
+    mut stdin: R,
+    stdout: &mut W,
+    stderr: &mut E,
+) -> io::Result<i32>
+where
+    R: Read,
+    W: Write,
+    E: Write,
+{
+    if args.is_empty() || ["help", "--help", "-h"].contains(&args[0].as_str()) {
+        write!(stdout, "{}", USAGE)?;
+        return Ok(if args.is_empty() { 2 } else { 0 });
+    }
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(message) => {
+            writeln!(stderr, "awarecs: {}\n\n{}", message, USAGE)?;
+            return Ok(2);
+        }
+    };
+
+    let mut status = 0;
+    let many = args.files.len() > 1;
+    for name in args.files.iter() {
+        let text = if name == "-" {
+            let mut text = String::new();
+            stdin.read_to_string(&mut text).map(|_| text)
+        } else {
+            fs::read_to_string(name)
+        };
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                writeln!(stderr, "awarecs: cannot read {}: {}", name, e)?;
+                status = 2;
+                continue;
+            }
+        };
+        let name = if name == "-" {
+            "<stdin>"
+        } else {
+            name.as_str()
+        };
+        if many && args.command != Command::Check {
+            writeln!(stdout, "==> {} <==", name)?;
+        }
+        let file_status = run_file(&args, name, &text, stdout, stderr)?;
+        status = status.max(file_status);
+    }
+    Ok(status)
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut format = None;
+    let mut options = LexerOptions::default();
+    let mut literate = false;
+    let mut files = vec![];
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--format" => match rest.next() {
+                Some(f) => format = Some(f.clone()),
+                None => return Err("--format needs a value".to_string()),
+            },
+            "--literate" => literate = true,
+            "--cs1" => options.dialect = Dialect::CoffeeScript1,
+            "--recover" => options.recover = true,
+            "-" if files.contains(arg) => return Err("stdin (-) can only be read once".to_string()),
+            "-" => files.push(arg.clone()),
+            _ if arg.starts_with("--format=") => {
+                format = Some(arg["--format=".len()..].to_string())
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => files.push(arg.clone()),
+        }
+    }
+    if files.is_empty() {
+        files.push("-".to_string());
+    }
+
+    let format = format.as_deref();
+    let command = match (args[0].as_str(), format) {
+        ("tokens", None) => Command::Tokens(TokenFormat::Text),
+        ("tokens", Some(f)) => match TokenFormat::from_name(f) {
+            Some(f) => Command::Tokens(f),
+            None => return Err(format!("tokens can't print {}", f)),
+        },
+        ("check", None) => Command::Check,
+        ("check", Some(_)) => return Err("check has no --format".to_string()),
+        ("highlight", None) | ("highlight", Some("ansi")) => {
+            Command::Highlight(HighlightFormat::Ansi)
+        }
+        ("highlight", Some("html")) => Command::Highlight(HighlightFormat::Html),
+        ("highlight", Some("html-page")) => Command::Highlight(HighlightFormat::HtmlPage),
+        ("highlight", Some(f)) => return Err(format!("highlight can't print {}", f)),
+        (command, _) => return Err(format!("no such command {}", command)),
+    };
+    Ok(Args {
+        command,
+        options,
+        literate,
+        files,
+    })
+}
+
+fn run_file<W, E>(
+    args: &Args,
+    name: &str,
+    text: &str,
+    stdout: &mut W,
+    stderr: &mut E,
+) -> io::Result<i32>
+where
+    W: Write,
+    E: Write,
+{
+    let mut options = LexerOptions {
+        literate: args.literate || is_literate_name(name),
+        ..args.options
+    };
+    match args.command {
+        Command::Check | Command::Highlight(_) => options.recover = true,
+        Command::Tokens(_) => {}
+    }
+
+    let lines = LineIndex::new(text);
+    let mut lexer = Lexer::with_options(text, &options);
+    let mut tokens = vec![];
+    let mut stopped = None;
+    for token in lexer.by_ref() {
+        match token {
+            Ok(token) => tokens.push(token),
+            Err(e) => stopped = Some(e),
+        }
+    }
+    let mut errors = lexer.errors().to_vec();
+    errors.extend(stopped);
+
+    match args.command {
+        // Even recovering, some errors (a limit, eof in a string) stop the lex, and then there's nothing whole to print.
+        Command::Tokens(format) => {
+            if stopped.is_none() {
+                write!(stdout, "{}", format.render(&tokens, text, &options))?;
+            }
+        }
+        // Highlighting what got lexed is still worth more than nothing. Past where it stopped, the code comes out
+        //   unstyled.
+        Command::Highlight(format) => {
+            match format {
+                HighlightFormat::Ansi => write!(
+                    stdout,
+                    "{}",
+                    to_ansi(&tokens, text, &options, &AnsiTheme::default())
+                )?,
+                HighlightFormat::Html => writeln!(stdout, "{}", to_html(&tokens, text, &options))?,
+                HighlightFormat::HtmlPage => {
+                    write!(stdout, "{}", to_html_page(&tokens, text, &options, name))?
+                }
+            }
+            return Ok(0);
+        }
+        Command::Check => {
+            for diagnostic in lexer.diagnostics() {
+                write_diagnostic(
+                    name,
+                    &lines,
+                    "warning",
+                    &diagnostic.kind.to_string(),
+                    diagnostic.position.index,
+                    stderr,
+                )?;
+            }
+        }
+    }
+    write_errors(name, &lines, &errors, stderr)?;
+    Ok(if errors.is_empty() { 0 } else { 1 })
+}
+
+fn write_errors<E: Write>(
+    name: &str,
+    lines: &LineIndex,
+    errors: &[LexError],
+    stderr: &mut E,
+) -> io::Result<()> {
+    for error in errors {
+        write_diagnostic(
+            name,
+            lines,
+            "error",
+            &error.kind.to_string(),
+            error.position.index,
+            stderr,
+        )?;
+    }
+    Ok(())
+}
+
+// path:line:col: severity: message, then the line and a caret under index. Tabs before the spot are kept so the
+//   caret lines up however wide the terminal shows them. lines is the file's, built once for all its diagnostics.
+fn write_diagnostic<E: Write>(
+    name: &str,
+    lines: &LineIndex,
+    severity: &str,
+    message: &str,
+    index: usize,
+    stderr: &mut E,
+) -> io::Result<()> {
+    let at = lines.line_col(index);
+    let location = Location {
+        name,
+        line_no: at.line_no,
+        col_no: at.char_col,
+    };
+    writeln!(stderr, "{}: {}: {}", location, severity, message)?;
+    let line = &lines.code()[lines.line_range(at.line_no).unwrap()];
+    let pad: String = line[..at.utf8_col]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    writeln!(stderr, "    {}\n    {}^", line, pad)
+}
+
+#[cfg(test)]
+mod test {
+    use super::run;
+    use std::env;
+    use std::fs;
+
+    // The exit code, stdout and stderr of awarecs with args, fed stdin.
+    fn awarecs(args: &[&str], stdin: &str) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let status = run(&args, stdin.as_bytes(), &mut stdout, &mut stderr);
+        (
+            status,
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        )
+    }
+
+    #[test]
+    fn tokens_should_print_in_each_format() {
+        let (status, out, err) = awarecs(&["tokens"], "40 + 2\n");
+        assert_eq!((status, err.as_str()), (0, ""));
+        assert_eq!(
+            out,
+            "[Number 40]\n[Whitespace  ]\n[Operator +]\n[Whitespace  ]\n[Number 2]\n[NewlineAndIndent \\n]\n"
+        );
+        let (_, out, _) = awarecs(&["tokens", "--format", "coffee"], "40 + 2\n");
+        assert_eq!(out, "[NUMBER 40] [+ +] [NUMBER 2] [TERMINATOR \\n]\n");
+        let (_, out, _) = awarecs(&["tokens", "--format=json", "-"], "x");
+        assert!(out.starts_with("[\n  {\"tag\": \"Word\", \"text\": \"x\""));
+    }
+
+    #[test]
+    fn errors_should_be_diagnostics_and_a_nonzero_exit() {
+        let code = "a = 1\n\tb ? c\n";
+        let (status, out, err) = awarecs(&["tokens"], code);
+        assert_eq!((status, out.as_str()), (1, ""));
+        assert_eq!(
+            err,
+            "<stdin>:1:3: error: unexpected character '='\n    a = 1\n      ^\n"
+        );
+
+        // Recovering, the tokens come out too, and so does every error.
+        let (status, out, err) = awarecs(&["tokens", "--recover"], code);
+        assert_eq!(status, 1);
+        assert!(out.contains("[Error =]"));
+        assert_eq!(err.matches(": error: ").count(), 2);
+        assert!(
+            err.ends_with("<stdin>:2:4: error: unexpected character '?'\n    \tb ? c\n    \t  ^\n")
+        );
+
+        let (status, out, err) = awarecs(&["check"], "if x\n  y\n\tz ?\n");
+        assert_eq!((status, out.as_str()), (1, ""));
+        assert!(
+            err.starts_with("<stdin>:3:1: warning: indentation mixes tabs and spaces differently")
+        );
+        assert!(err.contains("<stdin>:3:4: error: unexpected character '?'"));
+        assert_eq!(awarecs(&["check"], "a + b").0, 0);
+    }
+
+    #[test]
+    fn highlight_should_never_fail_on_bad_code() {
+        let (status, out, _) = awarecs(&["highlight", "--format", "html"], "a = <b/>");
+        assert_eq!(status, 0);
+        assert!(
+            out.starts_with("<pre class=\"awarecs\"><code><span class=\"cs-variable\">a</span> = ")
+        );
+        let (status, out, _) = awarecs(&["highlight"], "if x");
+        assert_eq!(status, 0);
+        assert_eq!(out, "\x1b[1;35mif\x1b[0m x");
+
+        // Nested too deep to lex: what came before is highlighted, and the rest is still printed.
+        let code = format!("a \"{}x", "#{\"".repeat(300));
+        let (status, out, _) = awarecs(&["highlight", "--format", "html"], &code);
+        assert_eq!(status, 0);
+        assert!(
+            out.starts_with("<pre class=\"awarecs\"><code><span class=\"cs-variable\">a</span> ")
+        );
+        assert!(out.ends_with("#{&quot;#{&quot;x</code></pre>\n"));
+    }
+
+    #[test]
+    fn files_should_be_read_and_named_and_literate_by_extension() {
+        let dir = env::temp_dir().join(format!("awarecs-cli-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.coffee");
+        let b = dir.join("b.litcoffee");
+        fs::write(&a, "x ?").unwrap();
+        fs::write(&b, "Prose ?\n\n    y\n").unwrap();
+        let a = a.to_str().unwrap().to_string();
+        let b = b.to_str().unwrap().to_string();
+        let missing = dir.join("missing.coffee").to_str().unwrap().to_string();
+
+        let (status, out, err) = awarecs(&["check", &a, &b], "");
+        assert_eq!((status, out.as_str()), (1, ""));
+        assert!(err.starts_with(&format!("{}:1:3: error", a)));
+        assert_eq!(err.matches(": error: ").count(), 1);
+
+        let (status, out, err) = awarecs(&["tokens", "--format", "coffee", &b, &missing], "");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(status, 2);
+        assert_eq!(
+            out,
+            format!("==> {} <==\n[IDENTIFIER y] [TERMINATOR \\n]\n", b)
+        );
+        assert!(err.starts_with(&format!("awarecs: cannot read {}", missing)));
+    }
+
+    #[test]
+    fn bad_arguments_should_print_usage_and_exit_2() {
+        for args in [
+            vec!["frobnicate"],
+            vec!["tokens", "--format", "yaml"],
+            vec!["check", "--format", "json"],
+            vec!["tokens", "--verbose"],
+            vec!["tokens", "--format"],
+            vec!["tokens", "-", "a.coffee", "-"],
+        ]
+        .iter()
+        {
+            let (status, _, err) = awarecs(args, "");
+            assert_eq!(status, 2, "for {:?}", args);
+            assert!(err.contains("usage: awarecs"), "for {:?}", args);
+        }
+        let (status, out, _) = awarecs(&["help"], "");
+        assert_eq!(status, 0);
+        assert!(out.starts_with("usage: awarecs"));
+    }
+}
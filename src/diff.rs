@@ -0,0 +1,423 @@
+use chomp::{position_after, Position};
+use lex::{SourceCodeProvider, Token};
+use std::fmt;
+
+// Lines up an expected token stream (the "[Tag text]" strings the golden tests are written in) against what the lexer
+//   actually produced, so a failing case tells you *where* it went wrong instead of just "these two strings differ".
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DiffEntry {
+    pub tag: String,
+    pub text: String,
+}
+
+impl DiffEntry {
+    pub fn new(tag: &str, text: &str) -> DiffEntry {
+        DiffEntry {
+            tag: tag.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    // Parses the "[Tag text]" form produced by Token::text. Anything that doesn't look like that is kept whole as the
+    //   text, with an empty tag, so a typo in a golden file still shows up in the diff rather than being swallowed.
+    pub fn parse(rendered: &str) -> DiffEntry {
+        if rendered.starts_with('[') && rendered.ends_with(']') && rendered.len() >= 2 {
+            let inner = &rendered[1..rendered.len() - 1];
+            match inner.find(' ') {
+                Some(space) => return DiffEntry::new(&inner[..space], &inner[space + 1..]),
+                None => return DiffEntry::new(inner, ""),
+            }
+        }
+        DiffEntry::new("", rendered)
+    }
+
+    pub fn from_token<TSource>(token: &Token, source: &TSource) -> DiffEntry
+    where
+        TSource: SourceCodeProvider,
+    {
+        let span = token.span;
+        DiffEntry::new(
+            &format!("{:?}", token.tag),
+            &source.get_source_code()[span.start_pos.index..span.end_pos.index],
+        )
+    }
+}
+
+impl fmt::Display for DiffEntry {
+    // Escaped, so a NewlineAndIndent doesn't tear the report apart.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{} {}]", self.tag, escape(&self.text))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DiffEdit {
+    Same(DiffEntry, Position),
+    // In the actual stream, but not expected.
+    Inserted(DiffEntry, Position),
+    // Expected, but missing from the actual stream. The position is where it should have shown up.
+    Deleted(DiffEntry, Position),
+    // Expected one thing, got another in the same slot.
+    Changed {
+        expected: DiffEntry,
+        actual: DiffEntry,
+        position: Position,
+    },
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct DiffSummary {
+    pub same: usize,
+    pub inserted: usize,
+    pub deleted: usize,
+    pub changed: usize,
+}
+
+impl DiffSummary {
+    pub fn is_match(&self) -> bool {
+        self.inserted == 0 && self.deleted == 0 && self.changed == 0
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} same, {} inserted, {} deleted, {} changed",
+            self.same, self.inserted, self.deleted, self.changed
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenDiff {
+    pub edits: Vec<DiffEdit>,
+}
+
+impl TokenDiff {
+    pub fn summary(&self) -> DiffSummary {
+        let mut summary = DiffSummary::default();
+        for edit in self.edits.iter() {
+            match *edit {
+                DiffEdit::Same(..) => summary.same += 1,
+                DiffEdit::Inserted(..) => summary.inserted += 1,
+                DiffEdit::Deleted(..) => summary.deleted += 1,
+                DiffEdit::Changed { .. } => summary.changed += 1,
+            }
+        }
+        summary
+    }
+
+    pub fn is_match(&self) -> bool {
+        self.summary().is_match()
+    }
+}
+
+impl fmt::Display for TokenDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for edit in self.edits.iter() {
+            match *edit {
+                DiffEdit::Same(ref entry, pos) => writeln!(f, "  {:>8}  {}", at(pos), entry)?,
+                DiffEdit::Inserted(ref entry, pos) => writeln!(f, "+ {:>8}  {}", at(pos), entry)?,
+                DiffEdit::Deleted(ref entry, pos) => writeln!(f, "- {:>8}  {}", at(pos), entry)?,
+                DiffEdit::Changed {
+                    ref expected,
+                    ref actual,
+                    position,
+                } => {
+                    let what = if expected.tag != actual.tag && expected.text != actual.text {
+                        "tag and text"
+                    } else if expected.tag != actual.tag {
+                        "tag"
+                    } else {
+                        "text"
+                    };
+                    writeln!(
+                        f,
+                        "~ {:>8}  {} => {} ({} changed)",
+                        at(position),
+                        expected,
+                        actual,
+                        what
+                    )?
+                }
+            }
+        }
+        write!(f, "{}", self.summary())
+    }
+}
+
+fn at(pos: Position) -> String {
+    format!("{}:{}", pos.line_no, pos.col_no)
+}
+
+pub fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+// The inverse of escape, for reading expected tokens out of a file that holds one per line.
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+pub fn diff_tokens<TSource>(expected: &[&str], actual: &[Token], source: &TSource) -> TokenDiff
+where
+    TSource: SourceCodeProvider,
+{
+    let expected: Vec<DiffEntry> = expected.iter().map(|e| DiffEntry::parse(e)).collect();
+    let actual: Vec<(DiffEntry, Position)> = actual
+        .iter()
+        .map(|t| (DiffEntry::from_token(t, source), t.span.start_pos))
+        .collect();
+    diff_entries(&expected, &actual, source_end(source.get_source_code()))
+}
+
+// Where a token missing at the very end "should have been". Counted the same way the chomper counts.
+fn source_end(code: &str) -> Position {
+    position_after(code, Position::start_of(code), code.len())
+}
+
+pub fn diff_entries(
+    expected: &[DiffEntry],
+    actual: &[(DiffEntry, Position)],
+    eof: Position,
+) -> TokenDiff {
+    // Peel off the common prefix and suffix first. Golden failures are almost always a small hole in a long stream,
+    //   and this keeps the LCS table down to the size of the hole.
+    let mut prefix = 0;
+    while prefix < expected.len() && prefix < actual.len() && expected[prefix] == actual[prefix].0 {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < expected.len() - prefix
+        && suffix < actual.len() - prefix
+        && expected[expected.len() - 1 - suffix] == actual[actual.len() - 1 - suffix].0
+    {
+        suffix += 1;
+    }
+
+    let exp_mid = &expected[prefix..expected.len() - suffix];
+    let act_mid = &actual[prefix..actual.len() - suffix];
+
+    // lcs[i][j] is the length of the longest common subsequence of exp_mid[i..] and act_mid[j..].
+    let width = act_mid.len() + 1;
+    let mut lcs = vec![0u32; (exp_mid.len() + 1) * width];
+    for i in (0..exp_mid.len()).rev() {
+        for j in (0..act_mid.len()).rev() {
+            lcs[i * width + j] = if exp_mid[i] == act_mid[j].0 {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                ::std::cmp::max(lcs[(i + 1) * width + j], lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    // Position of the actual token at index j, or eof if we've run off the end.
+    let position_of = |j: usize| -> Position {
+        if j < actual.len() {
+            actual[j].1
+        } else {
+            eof
+        }
+    };
+
+    let mut edits = vec![];
+    for &(ref entry, pos) in actual[..prefix].iter() {
+        edits.push(DiffEdit::Same(entry.clone(), pos));
+    }
+
+    let (mut i, mut j) = (0, 0);
+    // Deletions and insertions are held back until the run ends, so adjacent pairs can become Changed.
+    let mut deleted: Vec<(DiffEntry, Position)> = vec![];
+    let mut inserted: Vec<(DiffEntry, Position)> = vec![];
+    while i < exp_mid.len() || j < act_mid.len() {
+        if i < exp_mid.len() && j < act_mid.len() && exp_mid[i] == act_mid[j].0 {
+            flush_run(&mut edits, &mut deleted, &mut inserted);
+            edits.push(DiffEdit::Same(act_mid[j].0.clone(), act_mid[j].1));
+            i += 1;
+            j += 1;
+        } else if j < act_mid.len()
+            && (i == exp_mid.len() || lcs[i * width + j + 1] >= lcs[(i + 1) * width + j])
+        {
+            inserted.push(act_mid[j].clone());
+            j += 1;
+        } else {
+            deleted.push((exp_mid[i].clone(), position_of(prefix + j)));
+            i += 1;
+        }
+    }
+    flush_run(&mut edits, &mut deleted, &mut inserted);
+
+    for &(ref entry, pos) in actual[actual.len() - suffix..].iter() {
+        edits.push(DiffEdit::Same(entry.clone(), pos));
+    }
+
+    TokenDiff { edits }
+}
+
+fn flush_run(
+    edits: &mut Vec<DiffEdit>,
+    deleted: &mut Vec<(DiffEntry, Position)>,
+    inserted: &mut Vec<(DiffEntry, Position)>,
+) {
+    let paired = ::std::cmp::min(deleted.len(), inserted.len());
+    let mut deleted = deleted.drain(..);
+    let mut inserted = inserted.drain(..);
+    for _ in 0..paired {
+        let (expected, _) = deleted.next().unwrap();
+        let (actual, position) = inserted.next().unwrap();
+        edits.push(DiffEdit::Changed {
+            expected,
+            actual,
+            position,
+        });
+    }
+    for (entry, position) in deleted {
+        edits.push(DiffEdit::Deleted(entry, position));
+    }
+    for (entry, position) in inserted {
+        edits.push(DiffEdit::Inserted(entry, position));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_tokens, escape, unescape, DiffEdit, DiffEntry};
+    use lex::Lexer;
+
+    #[test]
+    fn identical_streams_should_match() {
+        let code = "40 + 2";
+        let tokens = Lexer::new(code).lex();
+        let diff = diff_tokens(
+            &[
+                "[Number 40]",
+                "[Whitespace  ]",
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[Number 2]",
+            ],
+            &tokens,
+            &code,
+        );
+        assert!(diff.is_match());
+        assert_eq!(diff.summary().same, 5);
+    }
+
+    #[test]
+    fn should_parse_rendered_tokens_including_ones_whose_text_has_spaces() {
+        assert_eq!(
+            DiffEntry::parse("[Whitespace   ]"),
+            DiffEntry::new("Whitespace", "  ")
+        );
+        assert_eq!(
+            DiffEntry::parse("[Comment # a b]"),
+            DiffEntry::new("Comment", "# a b")
+        );
+        assert_eq!(DiffEntry::parse("garbage"), DiffEntry::new("", "garbage"));
+    }
+
+    #[test]
+    fn should_report_missing_token_where_it_should_have_been() {
+        let code = "40+\n2";
+        let tokens = Lexer::new(code).lex();
+        let diff = diff_tokens(
+            &[
+                "[Number 40]",
+                "[Operator +]",
+                "[Operator +]",
+                "[NewlineAndIndent \n]",
+                "[Number 2]",
+            ],
+            &tokens,
+            &code,
+        );
+        let summary = diff.summary();
+        assert_eq!(summary.deleted, 1);
+        assert_eq!(summary.same, 4);
+        match diff
+            .edits
+            .iter()
+            .find(|e| matches!(**e, DiffEdit::Deleted(..)))
+        {
+            Some(&DiffEdit::Deleted(ref entry, pos)) => {
+                assert_eq!(entry, &DiffEntry::new("Operator", "+"));
+                assert_eq!((pos.line_no, pos.col_no), (1, 3));
+            }
+            other => panic!("expected a deletion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_report_extra_tokens_at_the_end_as_insertions() {
+        let code = "40 2";
+        let tokens = Lexer::new(code).lex();
+        let diff = diff_tokens(&["[Number 40]"], &tokens, &code);
+        let summary = diff.summary();
+        assert_eq!((summary.same, summary.inserted), (1, 2));
+        assert_eq!(
+            diff.edits[2],
+            DiffEdit::Inserted(DiffEntry::new("Number", "2"), tokens[2].span.start_pos)
+        );
+    }
+
+    #[test]
+    fn should_pair_a_deletion_and_insertion_in_the_same_slot_as_a_change() {
+        let code = "foo 42";
+        let tokens = Lexer::new(code).lex();
+        let diff = diff_tokens(
+            &["[Word foo]", "[Whitespace  ]", "[Word 42]"],
+            &tokens,
+            &code,
+        );
+        assert_eq!(diff.summary().changed, 1);
+        let report = format!("{}", diff);
+        assert!(report.contains("~      1:4  [Word 42] => [Number 42] (tag changed)"));
+        assert!(report.ends_with("2 same, 0 inserted, 0 deleted, 1 changed"));
+    }
+
+    #[test]
+    fn should_not_panic_when_expected_is_longer_than_actual() {
+        let code = "";
+        let tokens = Lexer::new(code).lex();
+        let diff = diff_tokens(&["[Number 40]", "[Number 2]"], &tokens, &code);
+        assert_eq!(diff.summary().deleted, 2);
+    }
+
+    #[test]
+    fn escape_and_unescape_should_round_trip() {
+        let text = "a\\b\n\t\r c";
+        assert_eq!(escape(text), "a\\\\b\\n\\t\\r c");
+        assert_eq!(unescape(&escape(text)), text);
+    }
+}
@@ -0,0 +1,301 @@
+use chomp::newline_len;
+use lex::Token;
+use options::LexerOptions;
+use semantic::{classify, SemanticKind, SemanticToken};
+
+// Syntax highlighting, for a terminal (ANSI escapes) or a web page (HTML with a CSS class per SemanticKind). Every
+//   byte of the source comes out, in order, with only color added: whitespace, comments and line endings are left
+//   exactly as they were, so stripping the colors gives the source back.
+//
+//   let tokens = Lexer::new(code).lex();
+//   print!("{}", to_ansi(&tokens, code, &LexerOptions::default(), &AnsiTheme::default()));
+
+// The SGR parameters to color each kind with ("1;34" is bold blue). An empty string leaves that kind plain.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AnsiTheme {
+    colors: [&'static str; SemanticKind::ALL.len()],
+}
+
+impl AnsiTheme {
+    pub fn color(&self, kind: SemanticKind) -> &'static str {
+        self.colors[kind.index() as usize]
+    }
+
+    pub fn set_color(&mut self, kind: SemanticKind, sgr: &'static str) {
+        self.colors[kind.index() as usize] = sgr;
+    }
+}
+
+impl Default for AnsiTheme {
+    // Only the 16 basic colors, so it works in any terminal.
+    fn default() -> AnsiTheme {
+        let mut theme = AnsiTheme {
+            colors: [""; SemanticKind::ALL.len()],
+        };
+        theme.set_color(SemanticKind::Keyword, "1;35");
+        theme.set_color(SemanticKind::Property, "36");
+        theme.set_color(SemanticKind::Function, "1;34");
+        theme.set_color(SemanticKind::Parameter, "33");
+        theme.set_color(SemanticKind::Number, "32");
+        theme.set_color(SemanticKind::String, "31");
+        theme.set_color(SemanticKind::StringEscape, "1;31");
+        theme.set_color(SemanticKind::Regex, "35");
+        theme.set_color(SemanticKind::InterpolationPunctuation, "1;33");
+        theme.set_color(SemanticKind::Comment, "2");
+        theme.set_color(SemanticKind::Tag, "34");
+        theme.set_color(SemanticKind::Attribute, "36");
+        theme
+    }
+}
+
+// The class an HTML span gets for kind. DEFAULT_CSS has a rule for each.
+pub fn css_class(kind: SemanticKind) -> &'static str {
+    match kind {
+        SemanticKind::Keyword => "cs-keyword",
+        SemanticKind::Variable => "cs-variable",
+        SemanticKind::Property => "cs-property",
+        SemanticKind::Function => "cs-function",
+        SemanticKind::Parameter => "cs-parameter",
+        SemanticKind::Number => "cs-number",
+        SemanticKind::String => "cs-string",
+        SemanticKind::StringEscape => "cs-string-escape",
+        SemanticKind::Regex => "cs-regex",
+        SemanticKind::InterpolationPunctuation => "cs-interpolation",
+        SemanticKind::Comment => "cs-comment",
+        SemanticKind::Operator => "cs-operator",
+        SemanticKind::Tag => "cs-tag",
+        SemanticKind::Attribute => "cs-attribute",
+    }
+}
+
+// A light theme for the classes css_class hands out. to_html_page puts it in the page; to_html leaves styling to you.
+pub const DEFAULT_CSS: &str = "\
+pre.awarecs { background: #fafafa; color: #383a42; padding: 1em; tab-size: 4; }
+.cs-keyword { color: #a626a4; font-weight: bold; }
+.cs-variable { color: #383a42; }
+.cs-property { color: #0184bc; }
+.cs-function { color: #4078f2; }
+.cs-parameter { color: #986801; }
+.cs-number { color: #986801; }
+.cs-string { color: #50a14f; }
+.cs-string-escape { color: #0184bc; font-weight: bold; }
+.cs-regex { color: #c18401; }
+.cs-interpolation { color: #ca1243; }
+.cs-comment { color: #a0a1a7; font-style: italic; }
+.cs-operator { color: #383a42; }
+.cs-tag { color: #e45649; }
+.cs-attribute { color: #986801; }
+";
+
+// code with ANSI colors, going by theme. tokens are what the lexer made of code with options. Colors are reset at
+//   the end of every line and picked up again on the next, so a multiline comment stays colored in a pager.
+pub fn to_ansi(tokens: &[Token], code: &str, options: &LexerOptions, theme: &AnsiTheme) -> String {
+    let mut out = String::with_capacity(code.len() * 2);
+    walk(code, &classify(tokens, code, options), |text, kind| {
+        let sgr = kind.map_or("", |k| theme.color(k));
+        if sgr.is_empty() {
+            out.push_str(text);
+            return;
+        }
+        // Any line break the chomper takes for one: \n, \r\n or a lone \r.
+        let mut rest = text;
+        loop {
+            let end = rest.find(['\n', '\r']).unwrap_or(rest.len());
+            if end > 0 {
+                out.push_str("\x1b[");
+                out.push_str(sgr);
+                out.push('m');
+                out.push_str(&rest[..end]);
+                out.push_str("\x1b[0m");
+            }
+            if end == rest.len() {
+                break;
+            }
+            let line_break = end + newline_len(&rest[end..]);
+            out.push_str(&rest[end..line_break]);
+            rest = &rest[line_break..];
+        }
+    });
+    out
+}
+
+// code as a <pre class="awarecs"><code> block, for dropping into a page that has the CSS. Each token with a kind
+//   is a <span> with that kind's css_class.
+pub fn to_html(tokens: &[Token], code: &str, options: &LexerOptions) -> String {
+    let mut out = String::with_capacity(code.len() * 3);
+    out.push_str("<pre class=\"awarecs\"><code>");
+    walk(
+        code,
+        &classify(tokens, code, options),
+        |text, kind| match kind {
+            Some(kind) => {
+                out.push_str("<span class=\"");
+                out.push_str(css_class(kind));
+                out.push_str("\">");
+                escape_html_into(&mut out, text);
+                out.push_str("</span>");
+            }
+            None => escape_html_into(&mut out, text),
+        },
+    );
+    out.push_str("</code></pre>");
+    out
+}
+
+// A whole HTML document: to_html, with DEFAULT_CSS in the head.
+pub fn to_html_page(tokens: &[Token], code: &str, options: &LexerOptions, title: &str) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>");
+    escape_html_into(&mut out, title);
+    out.push_str("</title>\n<style>\n");
+    out.push_str(DEFAULT_CSS);
+    out.push_str("</style>\n</head>\n<body>\n");
+    out.push_str(&to_html(tokens, code, options));
+    out.push_str("\n</body>\n</html>\n");
+    out
+}
+
+// Hands every piece of code to f in order: each semantic token with its kind, and what's between them with None.
+fn walk<F>(code: &str, semantic: &[SemanticToken], mut f: F)
+where
+    F: FnMut(&str, Option<SemanticKind>),
+{
+    let mut at = 0;
+    for token in semantic {
+        if token.range.start > at {
+            f(&code[at..token.range.start], None);
+        }
+        f(&code[token.range.clone()], Some(token.kind));
+        at = token.range.end;
+    }
+    if at < code.len() {
+        f(&code[at..], None);
+    }
+}
+
+fn escape_html_into(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{css_class, to_ansi, to_html, to_html_page, AnsiTheme};
+    use lex::Lexer;
+    use options::LexerOptions;
+    use semantic::SemanticKind;
+
+    const CODE: &str = "\u{feff}# <top> & \"tail\"\r\nf = (a) -> a + \"x\\n#{b}\"\n\t###\n  here\n  ###\n<p c='d'>e</p>\n";
+
+    fn recovering() -> LexerOptions {
+        LexerOptions {
+            recover: true,
+            ..LexerOptions::default()
+        }
+    }
+
+    fn strip_ansi(text: &str) -> String {
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(at) = rest.find("\x1b[") {
+            out.push_str(&rest[..at]);
+            rest = &rest[at + rest[at..].find('m').unwrap() + 1..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn strip_html(text: &str) -> String {
+        let mut out = String::new();
+        let mut in_tag = false;
+        for c in text.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                _ if !in_tag => out.push(c),
+                _ => {}
+            }
+        }
+        out.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    }
+
+    #[test]
+    fn taking_the_colors_off_should_give_back_the_source() {
+        let options = recovering();
+        let tokens = Lexer::with_options(CODE, &options).lex();
+        let ansi = to_ansi(&tokens, CODE, &options, &AnsiTheme::default());
+        assert_eq!(strip_ansi(&ansi), CODE);
+        let html = to_html(&tokens, CODE, &options);
+        assert_eq!(strip_html(&html), CODE);
+    }
+
+    #[test]
+    fn ansi_should_color_by_kind_and_reset_at_line_ends() {
+        let code = "if x\n###\na\n###";
+        let mut theme = AnsiTheme::default();
+        theme.set_color(SemanticKind::Variable, "4");
+        let ansi = to_ansi(
+            &Lexer::new(code).lex(),
+            code,
+            &LexerOptions::default(),
+            &theme,
+        );
+        assert_eq!(
+            ansi,
+            "\x1b[1;35mif\x1b[0m \x1b[4mx\x1b[0m\n\x1b[2m###\x1b[0m\n\x1b[2ma\x1b[0m\n\x1b[2m###\x1b[0m"
+        );
+
+        // A lone \r ends a line too, and so does \r\n.
+        let code = "###\ra\r\n###";
+        let ansi = to_ansi(
+            &Lexer::new(code).lex(),
+            code,
+            &LexerOptions::default(),
+            &theme,
+        );
+        assert_eq!(
+            ansi,
+            "\x1b[2m###\x1b[0m\r\x1b[2ma\x1b[0m\r\n\x1b[2m###\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn html_should_escape_and_give_every_kind_a_class() {
+        let code = "a < \"&\\t\"";
+        let html = to_html(&Lexer::new(code).lex(), code, &LexerOptions::default());
+        assert_eq!(
+            html,
+            "<pre class=\"awarecs\"><code><span class=\"cs-variable\">a</span> \
+             <span class=\"cs-operator\">&lt;</span> <span class=\"cs-string\">&quot;</span>\
+             <span class=\"cs-string\">&amp;</span><span class=\"cs-string-escape\">\\t</span>\
+             <span class=\"cs-string\">&quot;</span></code></pre>"
+        );
+        let page = to_html_page(
+            &Lexer::new(code).lex(),
+            code,
+            &LexerOptions::default(),
+            "a<b",
+        );
+        assert!(page.contains("<title>a&lt;b</title>"));
+        assert!(page.contains(&html));
+        for kind in SemanticKind::ALL.iter() {
+            assert!(
+                super::DEFAULT_CSS.contains(&format!(".{} ", css_class(*kind))),
+                "no CSS for {:?}",
+                kind
+            );
+        }
+    }
+}
@@ -0,0 +1,606 @@
+use chomp::{position_after, Position, Span};
+use lex::{LexError, LexErrorKind, Lexer, LexerState, Limit, Token, TokenTag};
+use options::LexerOptions;
+use std::ops::Range;
+
+// Relexing after an edit, for the editor plugin. Instead of lexing the whole buffer on every keystroke:
+//
+//   1. Back up to the newline before the edit. Everything before that can't have changed.
+//   2. Pick the lexer state back up there. An IncrementalLexer keeps one per line; relex rebuilds it by replaying the
+//      old tokens (no text is scanned, but it's still every token before the edit).
+//   3. Lex the new text from there until we're past the edit *and* sitting on a spot where an old token started in
+//      exactly the same lexer state. From then on the old tokens are still right, just shifted.
+//
+// Only tokens come out. With LexerOptions::recover on, the Error tokens are the same as a full lex's, but the errors
+//   it would keep (Lexer::errors) aren't collected; lex the whole thing if you need those.
+//
+// The tests check every result against a full relex.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TextEdit {
+    // Byte range in the old source being replaced.
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+impl TextEdit {
+    pub fn new(range: Range<usize>, replacement: &str) -> TextEdit {
+        TextEdit {
+            range,
+            replacement: replacement.to_string(),
+        }
+    }
+
+    pub fn apply(&self, old_source: &str) -> String {
+        let mut result = String::with_capacity(old_source.len() + self.replacement.len());
+        result.push_str(&old_source[..self.range.start]);
+        result.push_str(&self.replacement);
+        result.push_str(&old_source[self.range.end..]);
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relexed {
+    pub tokens: Vec<Token>,
+    // How many of those tokens actually came out of the lexer. The rest were reused.
+    pub relexed_count: usize,
+}
+
+// A buffer that stays lexed as it's edited. It keeps the lexer state at the start of every line, so an edit only
+//   costs the lines it touches. The indentation diagnostics in those states (indent_style, last_indentation) aren't
+//   kept up to date past an edit; they never change the tokens.
+pub struct IncrementalLexer {
+    source: String,
+    tokens: Vec<Token>,
+    options: LexerOptions,
+    // The state just before each NewlineAndIndent token, with that token's index. In token order.
+    line_states: Vec<(usize, LexerState)>,
+}
+
+impl IncrementalLexer {
+    pub fn new(source: &str) -> Result<IncrementalLexer, LexError> {
+        IncrementalLexer::with_options(source, &LexerOptions::default())
+    }
+
+    pub fn with_options(
+        source: &str,
+        options: &LexerOptions,
+    ) -> Result<IncrementalLexer, LexError> {
+        let mut lexer = Lexer::with_options(source, options);
+        let mut tokens = vec![];
+        let mut line_states = vec![];
+        while lex_one(&mut lexer, &mut tokens, &mut line_states)? {}
+        Ok(IncrementalLexer {
+            source: source.to_string(),
+            tokens,
+            options: *options,
+            line_states,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    // Applies edit and relexes what it changed. Gives how many tokens came out of the lexer. On an error, the edit
+    //   isn't applied and nothing changes.
+    pub fn edit(&mut self, edit: &TextEdit) -> Result<usize, LexError> {
+        let new_source = edit.apply(&self.source);
+        // The last line that starts strictly before the edit, as in relex_into_with_options.
+        let line = self
+            .line_states
+            .partition_point(|&(i, _)| self.tokens[i].span.start_pos.index < edit.range.start);
+        let restart = match line {
+            0 => Restart {
+                token: 0,
+                state: start_state(&new_source, &self.options),
+                old_state: start_state(&self.source, &self.options),
+            },
+            _ => {
+                let (i, ref state) = self.line_states[line - 1];
+                Restart {
+                    token: i,
+                    state: state.clone(),
+                    old_state: state.clone(),
+                }
+            }
+        };
+        let spliced = splice(
+            &self.tokens,
+            &self.source,
+            &new_source,
+            edit,
+            &self.options,
+            restart,
+        )?;
+
+        // Keep the states before the restart, take the lexer's for what it relexed, and move the rest along with
+        //   their tokens.
+        let kept = line.saturating_sub(1);
+        let mut line_states = Vec::with_capacity(self.line_states.len());
+        line_states.extend(self.line_states.drain(..kept));
+        line_states.extend(spliced.line_states);
+        for (i, mut state) in self.line_states.drain(..) {
+            if i >= spliced.reused_from_old {
+                state.position = shift_position(state.position, spliced.old_end, spliced.new_end);
+                state.token_count =
+                    state.token_count - spliced.reused_from_old + spliced.reused_from_new;
+                line_states.push((i - spliced.reused_from_old + spliced.reused_from_new, state));
+            }
+        }
+
+        self.line_states = line_states;
+        self.source = new_source;
+        self.tokens = spliced.relexed.tokens;
+        Ok(spliced.relexed.relexed_count)
+    }
+}
+
+pub fn relex(old_tokens: &[Token], old_source: &str, edit: &TextEdit) -> Result<Relexed, LexError> {
+    let new_source = edit.apply(old_source);
+    relex_into(old_tokens, old_source, &new_source, edit)
+}
+
+// Same as relex, for when the caller already has the edited text.
+pub fn relex_into(
+    old_tokens: &[Token],
+    old_source: &str,
+    new_source: &str,
+    edit: &TextEdit,
+) -> Result<Relexed, LexError> {
+    relex_into_with_options(
+        old_tokens,
+        old_source,
+        new_source,
+        edit,
+        &LexerOptions::default(),
+    )
+}
+
+// For tokens that were lexed with options. Relexing with different ones than that gives tokens that match neither.
+pub fn relex_into_with_options(
+    old_tokens: &[Token],
+    old_source: &str,
+    new_source: &str,
+    edit: &TextEdit,
+    options: &LexerOptions,
+) -> Result<Relexed, LexError> {
+    // Restart at the last newline that starts before the edit. Strictly before: if the edit touches the newline
+    //   itself, the line before it is in play too.
+    let restart = old_tokens
+        .iter()
+        .rposition(|t| {
+            t.tag == TokenTag::NewlineAndIndent && t.span.start_pos.index < edit.range.start
+        })
+        .unwrap_or(0);
+
+    let mut old_state = start_state(old_source, options);
+    for token in old_tokens[..restart].iter() {
+        old_state.advance(token, old_source);
+    }
+    // Starting from the top, the edit may have changed what the first line is.
+    let state = match restart {
+        0 => start_state(new_source, options),
+        _ => old_state.clone(),
+    };
+    let restart = Restart {
+        token: restart,
+        state,
+        old_state,
+    };
+    let spliced = splice(old_tokens, old_source, new_source, edit, options, restart)?;
+    Ok(spliced.relexed)
+}
+
+fn start_state(code: &str, options: &LexerOptions) -> LexerState {
+    if options.literate {
+        LexerState::start_literate(code)
+    } else {
+        LexerState::start(code)
+    }
+}
+
+// Where relexing starts: old_tokens[token], with the lexer state before it in the new source and the old one.
+struct Restart {
+    token: usize,
+    state: LexerState,
+    old_state: LexerState,
+}
+
+struct Spliced {
+    relexed: Relexed,
+    // The state before each NewlineAndIndent the lexer made, with its index in relexed.tokens.
+    line_states: Vec<(usize, LexerState)>,
+    // Where the reused old tokens start, in old_tokens and in relexed.tokens. The ends of both if none were.
+    reused_from_old: usize,
+    reused_from_new: usize,
+    // Where the edit ends, in old and new coordinates.
+    old_end: Position,
+    new_end: Position,
+}
+
+fn splice(
+    old_tokens: &[Token],
+    old_source: &str,
+    new_source: &str,
+    edit: &TextEdit,
+    options: &LexerOptions,
+    restart: Restart,
+) -> Result<Spliced, LexError> {
+    let state = restart.state;
+    let mut old_state = restart.old_state;
+    // Counted from the restart point, not the top of the file.
+    let old_end = position_after(old_source, old_state.position, edit.range.end);
+    let new_end = position_after(
+        new_source,
+        state.position,
+        edit.range.start + edit.replacement.len(),
+    );
+
+    // A BOM only counts at the very start, so an edit that adds, removes or moves one changes what the whole file
+    //   starts with. Nothing after it can be reused then.
+    let new_start = Position::start_of(new_source).index;
+    let reusable = edit.range.start >= Position::start_of(old_source).index
+        && new_start == Position::start_of(old_source).index;
+
+    let mut tokens: Vec<Token> = old_tokens[..restart.token].to_vec();
+    let mut line_states = vec![];
+    let mut lexer = Lexer::resume_with_options(new_source, &state, options);
+
+    let mut old_next = restart.token;
+    let mut relexed_count = 0;
+    let mut reused_from_old = old_tokens.len();
+    let mut reused_from_new = None;
+
+    loop {
+        let here = lexer.state();
+        // The first token of a file is lexed differently (a #! line is a Shebang only there), so never sync up on
+        //   the top of either file, or on a Shebang.
+        if reusable && here.position.index >= new_end.index && here.position.index > new_start {
+            let old_index = here.position.index - new_end.index + old_end.index;
+            while old_next < old_tokens.len()
+                && old_tokens[old_next].span.start_pos.index < old_index
+            {
+                old_state.advance(&old_tokens[old_next], old_source);
+                old_next += 1;
+            }
+            if old_next > 0
+                && old_next < old_tokens.len()
+                && old_tokens[old_next].tag != TokenTag::Shebang
+                && old_tokens[old_next].span.start_pos.index == old_index
+                && same_state(&old_state, &here)
+            {
+                reused_from_old = old_next;
+                reused_from_new = Some(tokens.len());
+                for token in old_tokens[old_next..].iter() {
+                    tokens.push(Token::make(token.tag, shift(token.span, old_end, new_end)));
+                }
+                break;
+            }
+        }
+
+        if !lex_one(&mut lexer, &mut tokens, &mut line_states)? {
+            break;
+        }
+        relexed_count += 1;
+    }
+
+    // The resumed lexer counted the tokens before it, but not the reused ones after.
+    let max = options.limits.max_token_count;
+    if tokens.len() > max {
+        return Err(LexError::new(
+            LexErrorKind::LimitExceeded(Limit::TokenCount, max),
+            tokens[max].span.start_pos,
+        ));
+    }
+
+    Ok(Spliced {
+        reused_from_new: reused_from_new.unwrap_or(tokens.len()),
+        relexed: Relexed {
+            tokens,
+            relexed_count,
+        },
+        line_states,
+        reused_from_old,
+        old_end,
+        new_end,
+    })
+}
+
+// Lexes one more token onto tokens, saving the state before it if it starts a line. False at the end.
+fn lex_one(
+    lexer: &mut Lexer,
+    tokens: &mut Vec<Token>,
+    line_states: &mut Vec<(usize, LexerState)>,
+) -> Result<bool, LexError> {
+    let line_state = lexer.line_state();
+    match lexer.next() {
+        None => Ok(false),
+        Some(token) => {
+            let token = token?;
+            if let (TokenTag::NewlineAndIndent, Some(state)) = (token.tag, line_state) {
+                line_states.push((tokens.len(), state));
+            }
+            tokens.push(token);
+            Ok(true)
+        }
+    }
+}
+
+// Everything that decides the tokens from here on. Not the position or token count, which the edit shifts.
+fn same_state(old: &LexerState, new: &LexerState) -> bool {
+    old.modes == new.modes
+        && old.last_significant == new.last_significant
+        && old.indents == new.indents
+        && old.literate == new.literate
+}
+
+// Moves a position that's after the edit from old coordinates to new ones. Only positions on the same line as the
+//   end of the edit have their column moved.
+fn shift_position(p: Position, old_end: Position, new_end: Position) -> Position {
+    Position {
+        index: p.index - old_end.index + new_end.index,
+        line_no: p.line_no - old_end.line_no + new_end.line_no,
+        col_no: if p.line_no == old_end.line_no {
+            p.col_no - old_end.col_no + new_end.col_no
+        } else {
+            p.col_no
+        },
+    }
+}
+
+fn shift(span: Span, old_end: Position, new_end: Position) -> Span {
+    Span {
+        start_pos: shift_position(span.start_pos, old_end, new_end),
+        end_pos: shift_position(span.end_pos, old_end, new_end),
+        file: span.file,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{relex_into_with_options, IncrementalLexer, TextEdit};
+    use lex::{LexLimits, Lexer, LexerState};
+    use options::LexerOptions;
+
+    fn assert_relex_matches_full_lex(old_source: &str, edit: &TextEdit) -> Option<usize> {
+        assert_relex_matches_full_lex_with(&LexerOptions::default(), old_source, edit)
+    }
+
+    // Relexes both ways, with relex and with an IncrementalLexer, and checks them against a full lex. Gives how many
+    //   tokens were relexed, unless it failed.
+    fn assert_relex_matches_full_lex_with(
+        options: &LexerOptions,
+        old_source: &str,
+        edit: &TextEdit,
+    ) -> Option<usize> {
+        let old_tokens = Lexer::with_options(old_source, options).lex();
+        let new_source = edit.apply(old_source);
+        let full = Lexer::with_options(&new_source, options).try_lex();
+        let incremental =
+            relex_into_with_options(&old_tokens, old_source, &new_source, edit, options);
+        let mut buffer = IncrementalLexer::with_options(old_source, options).unwrap();
+        let buffered = buffer.edit(edit);
+        match (full, incremental, buffered) {
+            (Ok(full), Ok(incremental), Ok(buffered)) => {
+                assert_eq!(
+                    incremental.tokens, full,
+                    "relex disagrees with a full lex after {:?} on {:?}",
+                    edit, old_source
+                );
+                assert_eq!(buffered, incremental.relexed_count);
+                assert_eq!(buffer.tokens(), &full[..]);
+                assert_eq!(buffer.source(), new_source);
+                let fresh = IncrementalLexer::with_options(&new_source, options).unwrap();
+                assert_eq!(
+                    line_states(&buffer),
+                    line_states(&fresh),
+                    "the saved line states are off after {:?} on {:?}",
+                    edit,
+                    old_source
+                );
+                Some(incremental.relexed_count)
+            }
+            (Err(full), Err(incremental), Err(buffered)) => {
+                assert_eq!(incremental, full);
+                assert_eq!(buffered, full);
+                assert_eq!(buffer.source(), old_source);
+                None
+            }
+            (full, incremental, buffered) => panic!(
+                "full lex gave {:?} but relex gave {:?} and the buffer {:?} after {:?} on {:?}",
+                full, incremental, buffered, edit, old_source
+            ),
+        }
+    }
+
+    // Leaves out the indentation diagnostics, which aren't kept up to date.
+    fn line_states(buffer: &IncrementalLexer) -> Vec<(usize, LexerState)> {
+        buffer
+            .line_states
+            .iter()
+            .map(|&(i, ref state)| {
+                let mut state = state.clone();
+                state.indent_style = None;
+                state.last_indentation.clear();
+                (i, state)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn apply_should_splice_the_replacement_in() {
+        assert_eq!(TextEdit::new(3..4, "-").apply("40 + 2"), "40 - 2");
+        assert_eq!(TextEdit::new(0..0, "\n").apply("42"), "\n42");
+    }
+
+    #[test]
+    fn a_small_edit_should_only_relex_its_own_line() {
+        let mut source = String::new();
+        for i in 0..200 {
+            source.push_str(&format!("line{} + {}\n", i, i));
+        }
+        let at = source.find("line100").unwrap() + 4;
+        let relexed = assert_relex_matches_full_lex(&source, &TextEdit::new(at..at + 3, "x\n  y"));
+        assert!(relexed.unwrap() < 12, "relexed {:?} tokens", relexed);
+    }
+
+    #[test]
+    fn opening_a_string_should_relex_everything_after_it() {
+        let source = "a + b\nc + \"d\"\ne\n";
+        let at = source.find('b').unwrap();
+        assert_relex_matches_full_lex(source, &TextEdit::new(at..at, "\""));
+    }
+
+    #[test]
+    fn every_small_edit_everywhere_should_match_a_full_relex() {
+        let sources = [
+            "40 + 2\n  a - b # c\n    d\n  e\nf",
+            "x \"one #{two} three\"\n  \"\"\"\n  four\n  \"\"\" + 5\n",
+            "a\n### here\ncomment ###\n  b / c /d/g\n///\n e #{f}\n///",
+            "a\r\n  b \"c\r\n d\"\r\n  ### e\r\n ###\re",
+            "a + <p x='1'>\n  hi {b}\n  <c/>{ {d} }</p>\ne < f",
+            "",
+        ];
+        let inserts = [
+            "x", " ", "\n", "\n  ", "\"", "#", "###", "/", "#{", "}", "\"\"\"", "9", "<", "{",
+        ];
+
+        for source in sources.iter() {
+            let editable = source.len();
+            for start in 0..editable + 1 {
+                for insert in inserts.iter() {
+                    assert_relex_matches_full_lex(source, &TextEdit::new(start..start, insert));
+                }
+                for len in 1..4 {
+                    if start + len <= editable {
+                        assert_relex_matches_full_lex(
+                            source,
+                            &TextEdit::new(start..start + len, ""),
+                        );
+                        assert_relex_matches_full_lex(
+                            source,
+                            &TextEdit::new(start..start + len, "z\n"),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn relexing_with_options_should_match_a_full_lex_with_them() {
+        let options = LexerOptions {
+            literate: true,
+            recover: true,
+            ..LexerOptions::default()
+        };
+        let source = "Some prose.\n\n    a + ?\n    \"b\n\nMore prose.\n\n\tc";
+        for start in 0..source.len() + 1 {
+            for insert in ["x", "\n", "\n\n    ", "\"", "?"].iter() {
+                assert_relex_matches_full_lex_with(
+                    &options,
+                    source,
+                    &TextEdit::new(start..start, insert),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn relexing_with_limits_should_stop_where_a_full_lex_does() {
+        let options = LexerOptions {
+            limits: LexLimits {
+                max_token_count: 14,
+                max_indent_depth: 2,
+                ..LexLimits::unlimited()
+            },
+            ..LexerOptions::default()
+        };
+        let source = "a + b\n  c\n    d\ne";
+        let mut failed = 0;
+        for start in 0..source.len() + 1 {
+            for insert in ["x", " ", "\n", "\n      ", "\"#{"].iter() {
+                let edit = TextEdit::new(start..start, insert);
+                if assert_relex_matches_full_lex_with(&options, source, &edit).is_none() {
+                    failed += 1;
+                }
+            }
+            if start < source.len() {
+                assert_relex_matches_full_lex_with(
+                    &options,
+                    source,
+                    &TextEdit::new(start..start + 1, ""),
+                );
+            }
+        }
+        assert!(failed > 10, "only {} edits went over a limit", failed);
+    }
+
+    #[test]
+    fn a_buffer_should_stay_lexed_through_many_edits() {
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("f{} + a\n  a + \"#{{b}} {}\"\n", i, i));
+        }
+        let mut buffer = IncrementalLexer::new(&source).unwrap();
+        let mut at = 0;
+        for (n, insert) in ["x", "\n  y", " ", "# c", "/", "\n"].iter().enumerate() {
+            // The start of a line further down each time.
+            at = buffer
+                .source()
+                .match_indices('\n')
+                .nth(100 + 20 * n)
+                .unwrap()
+                .0
+                + 1;
+            let relexed = buffer.edit(&TextEdit::new(at..at, insert)).unwrap();
+            assert_eq!(buffer.tokens(), &Lexer::new(buffer.source()).lex()[..]);
+            assert!(relexed < 40, "relexed {} tokens for {:?}", relexed, insert);
+        }
+        let fresh = IncrementalLexer::new(buffer.source()).unwrap();
+        assert_eq!(line_states(&buffer), line_states(&fresh));
+
+        // An edit that doesn't lex leaves the buffer alone.
+        let before = buffer.tokens().to_vec();
+        assert!(buffer.edit(&TextEdit::new(at..at, "?")).is_err());
+        assert_eq!(buffer.tokens(), &before[..]);
+    }
+
+    #[test]
+    fn relexing_the_top_of_the_file_should_match_a_full_lex() {
+        // A #! that isn't first anymore is a Comment, and a BOM that isn't first is an error.
+        assert_relex_matches_full_lex("#!", &TextEdit::new(0..0, " \n\t"));
+        assert_relex_matches_full_lex("\u{feff}/a", &TextEdit::new(0..0, "\r\n\r"));
+
+        let sources = [
+            "#!",
+            "\u{feff}/a",
+            " \n#!",
+            "#!x\n  y",
+            "\u{feff}#! a\nb",
+            "x\n#!",
+        ];
+        let inserts = [" \n\t", "\r\n\r", "\u{feff}", "#!", "x", "\n", " "];
+        for source in sources.iter() {
+            let boundaries: Vec<usize> = source
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(Some(source.len()))
+                .collect();
+            for (n, &start) in boundaries.iter().enumerate() {
+                for insert in inserts.iter() {
+                    assert_relex_matches_full_lex(source, &TextEdit::new(start..start, insert));
+                }
+                for &end in boundaries[n + 1..].iter().take(3) {
+                    assert_relex_matches_full_lex(source, &TextEdit::new(start..end, ""));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use chomp::Position;
+use std::fmt;
+
+// Indentation sanity checks. Like the reference lexer, we measure indentation in chars, so a tab and a space are
+//   the same width as far as nesting goes. That's fine as long as a file sticks to one or the other; when it
+//   doesn't, the nesting silently comes out wrong. These checks run on every line that counts for indentation and
+//   leave an IndentDiagnostic behind (see Lexer::diagnostics) instead of failing the lex.
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum IndentStyle {
+    Tabs,
+    // How many spaces the first indented line used.
+    Spaces(usize),
+}
+
+impl IndentStyle {
+    // The style a line's indentation implies, or None if it's mixed (or empty) and doesn't say.
+    pub fn of(indentation: &str) -> Option<IndentStyle> {
+        if indentation.is_empty() {
+            None
+        } else if indentation.chars().all(|c| c == '\t') {
+            Some(IndentStyle::Tabs)
+        } else if indentation.chars().all(|c| c == ' ') {
+            Some(IndentStyle::Spaces(indentation.len()))
+        } else {
+            None
+        }
+    }
+
+    fn allows(&self, c: char) -> bool {
+        match *self {
+            IndentStyle::Tabs => c == '\t',
+            IndentStyle::Spaces(_) => c == ' ',
+        }
+    }
+}
+
+impl fmt::Display for IndentStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndentStyle::Tabs => write!(f, "tabs"),
+            IndentStyle::Spaces(width) => write!(f, "{} spaces", width),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum IndentDiagnosticKind {
+    // The line's indentation and the previous line's don't start the same way (say, a tab where the block above
+    //   used spaces), so which block the line belongs to depends on how wide you think a tab is.
+    InconsistentWithBlock,
+    // The line uses a different kind of whitespace than the rest of the file. Carries the file's style.
+    InconsistentWithFile(IndentStyle),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct IndentDiagnostic {
+    pub kind: IndentDiagnosticKind,
+    // The start of the offending line.
+    pub position: Position,
+}
+
+impl fmt::Display for IndentDiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndentDiagnosticKind::InconsistentWithBlock => write!(
+                f,
+                "indentation mixes tabs and spaces differently from the line before"
+            ),
+            IndentDiagnosticKind::InconsistentWithFile(style) => write!(
+                f,
+                "indentation doesn't match the rest of the file ({})",
+                style
+            ),
+        }
+    }
+}
+
+impl fmt::Display for IndentDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}:{}",
+            self.kind, self.position.line_no, self.position.col_no
+        )
+    }
+}
+
+// Checks one line's indentation against the line before it (previous) and the file's style, picking up the style
+//   from the first indented line that has a clear one. Updates both for the next line. At most one problem is
+//   reported per line; the block one wins because it's the one that can change the nesting.
+pub fn check_indentation(
+    style: &mut Option<IndentStyle>,
+    previous: &mut String,
+    indentation: &str,
+) -> Option<IndentDiagnosticKind> {
+    let consistent =
+        indentation.starts_with(previous.as_str()) || previous.starts_with(indentation);
+    previous.clear();
+    previous.push_str(indentation);
+    if !consistent {
+        return Some(IndentDiagnosticKind::InconsistentWithBlock);
+    }
+
+    match *style {
+        None => {
+            *style = IndentStyle::of(indentation);
+            None
+        }
+        Some(s) if indentation.chars().any(|c| !s.allows(c)) => {
+            Some(IndentDiagnosticKind::InconsistentWithFile(s))
+        }
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_indentation, IndentDiagnosticKind, IndentStyle};
+
+    fn check_all(lines: &[&str]) -> (Option<IndentStyle>, Vec<Option<IndentDiagnosticKind>>) {
+        let mut style = None;
+        let mut previous = String::new();
+        let results = lines
+            .iter()
+            .map(|l| check_indentation(&mut style, &mut previous, l))
+            .collect();
+        (style, results)
+    }
+
+    #[test]
+    fn should_pick_up_the_style_from_the_first_indented_line() {
+        assert_eq!(
+            check_all(&["", "  ", "    ", ""]).0,
+            Some(IndentStyle::Spaces(2))
+        );
+        assert_eq!(check_all(&["", "\t", "\t\t"]).0, Some(IndentStyle::Tabs));
+        assert_eq!(check_all(&["", ""]).0, None);
+    }
+
+    #[test]
+    fn consistent_files_should_not_get_any_diagnostics() {
+        let (_, results) = check_all(&["", "  ", "    ", "  ", "", "  "]);
+        assert!(results.iter().all(|r| r.is_none()));
+        let (_, results) = check_all(&["\t", "\t\t", "", "\t"]);
+        assert!(results.iter().all(|r| r.is_none()));
+    }
+
+    #[test]
+    fn a_tab_under_spaces_should_be_inconsistent_with_its_block() {
+        let (_, results) = check_all(&["  ", "\t"]);
+        assert_eq!(
+            results[1],
+            Some(IndentDiagnosticKind::InconsistentWithBlock)
+        );
+        let (_, results) = check_all(&["\t", "\t  ", "  "]);
+        assert_eq!(
+            results[2],
+            Some(IndentDiagnosticKind::InconsistentWithBlock)
+        );
+    }
+
+    #[test]
+    fn a_new_block_in_the_other_style_should_be_inconsistent_with_the_file() {
+        let (_, results) = check_all(&["  ", "", "\t"]);
+        assert_eq!(
+            results[2],
+            Some(IndentDiagnosticKind::InconsistentWithFile(
+                IndentStyle::Spaces(2)
+            ))
+        );
+        let (_, results) = check_all(&["\t", "\t  "]);
+        assert_eq!(
+            results[1],
+            Some(IndentDiagnosticKind::InconsistentWithFile(
+                IndentStyle::Tabs
+            ))
+        );
+    }
+}
@@ -1,4 +1,14 @@
 use chomp::*;
+use indent::{check_indentation, IndentDiagnostic, IndentStyle};
+use options::{Dialect, LexerOptions};
+use pattern::{
+    one_or_more, zero_or_more, Alt, Class, Literal, Optional, Pattern, Repeat, Seq, Until, NEWLINE,
+};
+use reader_source::ReaderSource;
+use rules::LexRules;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
 use TokenTag::*;
 
 // use collections::string::String;
@@ -14,6 +24,7 @@ use TokenTag::*;
 //     };
 // }
 
+#[allow(unused_macros)]
 macro_rules! crf {
     ($e:expr) => {
         println!("{:?} is {:?}", stringify!($e), $e);
@@ -35,8 +46,370 @@ macro_rules! crf {
 
 pub struct Lexer<'lexer> {
     chomper: Chomper<'lexer>,
+    modes: Vec<LexMode>,
+    // The last token that wasn't trivia. Decides whether a / starts a regex or divides.
+    last_significant: Option<TokenTag>,
+    // How many Interpolation entries are in modes. Kept separately so checking the limit doesn't mean walking the stack.
+    interpolation_depth: usize,
+    // Widths of the indentation levels currently open, innermost last.
+    indents: Vec<usize>,
+    // Some when lexing Literate CoffeeScript.
+    literate: Option<LiterateState>,
+    indent_style: Option<IndentStyle>,
+    // The indentation of the last line that counted, to check the next one against.
+    last_indentation: String,
+    diagnostics: Vec<IndentDiagnostic>,
+    options: LexerOptions,
+    // The ones we kept going after, when options.recover is on.
+    errors: Vec<LexError>,
+    rules: LexRules,
+    token_count: usize,
+    // Where the last token started. Everything before it can be let go of (see CharSource::forget_before).
+    previous_token_start: usize,
+    // Set once we've returned an error. After that, the iterator is done.
+    failed: bool,
 }
 
+// What the lexer is in the middle of. The bottom of the stack is always Code; strings, interpolations and heregexes
+//   push on top of it and pop when they close. Because this lives here instead of on the Rust call stack, the whole
+//   lexer state can be captured between any two tokens (see LexerState).
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LexMode {
+    Code,
+    // Inside "...".
+    DoubleString,
+    // Inside the #{...} of a string or heregex.
+    Interpolation,
+    // Inside """...""".
+    Heredoc,
+    // Inside ///...///. A plain /.../ regex is a single token and never needs a mode.
+    Regex,
+    // Between the name and the > of a JSX opening tag, where the attributes go.
+    JsxTag,
+    // Between </ and > of a JSX closing tag.
+    JsxClosingTag,
+    // Between an element's tags: text, child elements and {expressions}.
+    JsxChildren,
+    // Inside the { } of a JSX attribute or child. Plain code again, up to the matching }.
+    JsxExpression,
+    // A { } inside a JsxExpression (an object literal, say), so its } doesn't close the expression.
+    Braces,
+}
+
+// Everything needed to pick lexing back up at a given spot. Editors save one of these per line (see
+//   Lexer::line_state) and relex from the nearest one instead of from the top of the file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexerState {
+    pub position: Position,
+    pub modes: Vec<LexMode>,
+    pub last_significant: Option<TokenTag>,
+    pub indents: Vec<usize>,
+    pub literate: Option<LiterateState>,
+    // Only used for diagnostics (see the indent module); they never change the tokens.
+    pub indent_style: Option<IndentStyle>,
+    pub last_indentation: String,
+    // How many tokens came before this spot, so a resumed lexer stops at LexLimits::max_token_count where a full
+    //   lex would.
+    pub token_count: usize,
+}
+
+impl LexerState {
+    // The state at the very top of a file (past the BOM, if it has one).
+    pub fn start(code: &str) -> LexerState {
+        LexerState::at(Position::start_of(code))
+    }
+
+    // The top of a file that starts at position.
+    fn at(position: Position) -> LexerState {
+        LexerState {
+            position,
+            modes: vec![LexMode::Code],
+            last_significant: None,
+            indents: vec![],
+            literate: None,
+            indent_style: None,
+            last_indentation: String::new(),
+            token_count: 0,
+        }
+    }
+
+    // The state at the very top of a .litcoffee file. Needs the code to know whether the first line is prose.
+    pub fn start_literate(code: &str) -> LexerState {
+        let mut state = LexerState::start(code);
+        state.literate = Some(LiterateState::TOP.next_line(&code[state.position.index..]));
+        state
+    }
+
+    // Moves the state past token, which the lexer produced out of code. Replaying a token list through this gives
+    //   the same state the lexer was in at each point, without lexing anything again.
+    pub fn advance(&mut self, token: &Token, code: &str) {
+        match token.tag {
+            OpenQuote if get_region(&code, token) == "\"\"\"" => self.modes.push(LexMode::Heredoc),
+            OpenQuote => self.modes.push(LexMode::DoubleString),
+            OpenInterpolation => self.modes.push(LexMode::Interpolation),
+            OpenRegex => self.modes.push(LexMode::Regex),
+            CloseQuote | CloseInterpolation | CloseRegex | CloseJsxExpression => {
+                self.modes.pop();
+            }
+            JsxTagOpen if get_region(&code, token) == "</" => {
+                self.modes.pop();
+                self.modes.push(LexMode::JsxClosingTag);
+            }
+            JsxTagOpen => self.modes.push(LexMode::JsxTag),
+            JsxTagClose if get_region(&code, token) == "/>" => {
+                self.modes.pop();
+            }
+            JsxTagClose => {
+                // The > of an opening tag goes on to the children; the > of a closing tag is the end of the element.
+                let tag = self.modes.pop();
+                if tag == Some(LexMode::JsxTag) {
+                    self.modes.push(LexMode::JsxChildren);
+                }
+            }
+            OpenJsxExpression => self.modes.push(LexMode::JsxExpression),
+            Operator if get_region(&code, token) == "{" => self.modes.push(LexMode::Braces),
+            Operator if get_region(&code, token) == "}" => {
+                self.modes.pop();
+            }
+            NewlineAndIndent => {
+                let line_start =
+                    token.span.start_pos.index + newline_len(&code[token.span.start_pos.index..]);
+                self.literate = self.literate.map(|l| l.next_line(&code[line_start..]));
+                if let Some(indentation) = line_indentation(token, code, self.literate) {
+                    check_indentation(
+                        &mut self.indent_style,
+                        &mut self.last_indentation,
+                        indentation,
+                    );
+                    indent_to(&mut self.indents, indentation.chars().count());
+                }
+            }
+            Comment | Shebang => {
+                self.literate = self.literate.map(LiterateState::past_prose);
+            }
+            _ => {}
+        }
+        if !token.tag.is_trivia() {
+            self.last_significant = Some(token.tag);
+        }
+        self.position = token.span.end_pos;
+        self.token_count += 1;
+    }
+}
+
+// Where we are in a .litcoffee file. There, only Markdown code blocks are code: lines indented four spaces (or a
+//   tab) that follow a blank line or another code line. Everything else is prose, which the lexer hands back as
+//   Comment tokens so it ends up as trivia. Same rules as the reference compiler's invertLiterate.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LiterateState {
+    // Whether an indented line here would be code. A prose line turns this off until the next blank line.
+    pub maybe_code: bool,
+    // The rest of the current line is prose.
+    pub in_prose: bool,
+}
+
+impl LiterateState {
+    const TOP: LiterateState = LiterateState {
+        maybe_code: true,
+        in_prose: false,
+    };
+
+    // The state for the line that starts text.
+    fn next_line(self, text: &str) -> LiterateState {
+        let line = text.split(['\n', '\r']).next().unwrap();
+        if self.maybe_code && code_block_prefix(line).is_some() {
+            return LiterateState {
+                maybe_code: true,
+                in_prose: false,
+            };
+        }
+        let blank = line.trim().is_empty();
+        LiterateState {
+            maybe_code: blank,
+            in_prose: !blank,
+        }
+    }
+
+    fn past_prose(self) -> LiterateState {
+        LiterateState {
+            maybe_code: self.maybe_code,
+            in_prose: false,
+        }
+    }
+}
+
+// How many chars of Markdown code block indentation line starts with, if it has any.
+fn code_block_prefix(line: &str) -> Option<usize> {
+    if line.starts_with("    ") {
+        return Some(4);
+    }
+    let spaces = line.chars().take_while(|&c| c == ' ').count();
+    if line[spaces..].starts_with('\t') {
+        Some(spaces + 1)
+    } else {
+        None
+    }
+}
+
+// The indentation of the line that newline_token starts, or None for a blank or comment-only line (those don't
+//   open or close anything, same as in the reference lexer). In a .litcoffee file, literate has to already be
+//   updated for that line; prose lines don't count and code lines are measured from the code block's edge.
+pub(crate) fn line_indentation<'c>(
+    newline_token: &Token,
+    code: &'c str,
+    literate: Option<LiterateState>,
+) -> Option<&'c str> {
+    indentation_in(
+        &code[newline_token.span.range()],
+        code[newline_token.span.end_pos.index..].chars().next(),
+        literate,
+    )
+}
+
+// line_indentation, given the newline token's text and the char that comes after it.
+fn indentation_in(
+    newline_and_indent: &str,
+    next: Option<char>,
+    literate: Option<LiterateState>,
+) -> Option<&str> {
+    if literate.is_some_and(|l| l.in_prose) {
+        return None;
+    }
+    match next {
+        None | Some('\n') | Some('\r') | Some('#') => None,
+        // Everything after the line break is indentation.
+        _ => {
+            let indentation = &newline_and_indent[newline_len(newline_and_indent)..];
+            match literate {
+                None => Some(indentation),
+                Some(_) => Some(&indentation[code_block_prefix(indentation).unwrap_or(0)..]),
+            }
+        }
+    }
+}
+
+fn indent_to(indents: &mut Vec<usize>, width: usize) {
+    while indents.last().is_some_and(|&top| top > width) {
+        indents.pop();
+    }
+    if width > *indents.last().unwrap_or(&0) {
+        indents.push(width);
+    }
+}
+
+// Guard rails for lexing input we don't trust (user uploads). Going over any of them ends lexing with a
+//   LexErrorKind::LimitExceeded instead of eating all the memory or time in the world.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LexLimits {
+    // How many #{ } can be open at once, counting the ones inside nested strings.
+    pub max_interpolation_depth: usize,
+    // How many indentation levels can be open at once.
+    pub max_indent_depth: usize,
+    pub max_token_count: usize,
+    // In bytes.
+    pub max_input_size: usize,
+}
+
+impl LexLimits {
+    pub fn unlimited() -> LexLimits {
+        LexLimits {
+            max_interpolation_depth: usize::MAX,
+            max_indent_depth: usize::MAX,
+            max_token_count: usize::MAX,
+            max_input_size: usize::MAX,
+        }
+    }
+}
+
+impl Default for LexLimits {
+    // Far beyond anything a person writes, well short of anything that hurts.
+    fn default() -> LexLimits {
+        LexLimits {
+            max_interpolation_depth: 256,
+            max_indent_depth: 256,
+            max_token_count: 10_000_000,
+            max_input_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Limit {
+    InterpolationDepth,
+    IndentDepth,
+    TokenCount,
+    InputSize,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LexErrorKind {
+    UnexpectedChar(char),
+    UnterminatedString,
+    UnterminatedInterpolation,
+    UnterminatedRegex,
+    // Hit eof inside a JSX element.
+    UnterminatedJsx,
+    // 010 or 09, with LexerOptions::strict_octal on.
+    LeadingZero,
+    // A tab in indentation, with LexerOptions::allow_tabs off.
+    TabIndentation,
+    // Which limit, and what it was set to.
+    LimitExceeded(Limit, usize),
+    // Reading the input failed (or it wasn't UTF-8), so the lex stops there. Only happens with a streaming source.
+    Unreadable(io::ErrorKind),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub position: Position,
+}
+
+impl LexError {
+    pub fn new(kind: LexErrorKind, position: Position) -> LexError {
+        LexError { kind, position }
+    }
+}
+
+// Just what went wrong, without where.
+impl fmt::Display for LexErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexErrorKind::UnexpectedChar(c) => write!(f, "unexpected character {:?}", c),
+            LexErrorKind::UnterminatedString => write!(f, "hit eof inside a string"),
+            LexErrorKind::UnterminatedInterpolation => {
+                write!(f, "hit eof inside a string interpolation")
+            }
+            LexErrorKind::UnterminatedRegex => write!(f, "hit eof inside a heregex"),
+            LexErrorKind::UnterminatedJsx => write!(f, "hit eof inside a JSX element"),
+            LexErrorKind::LeadingZero => write!(f, "number starts with a 0 (use 0o for octal)"),
+            LexErrorKind::TabIndentation => write!(f, "tab in indentation"),
+            LexErrorKind::LimitExceeded(limit, max) => {
+                let what = match limit {
+                    Limit::InterpolationDepth => "interpolation depth",
+                    Limit::IndentDepth => "indent depth",
+                    Limit::TokenCount => "token count",
+                    Limit::InputSize => "input size",
+                };
+                write!(f, "{} is over the limit of {}", what, max)
+            }
+            LexErrorKind::Unreadable(kind) => write!(f, "couldn't read the input ({})", kind),
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}:{}",
+            self.kind, self.position.line_no, self.position.col_no
+        )
+    }
+}
+
+impl Error for LexError {}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum TokenTag {
     Number,
@@ -44,6 +417,8 @@ pub enum TokenTag {
     Operator,
     Herecomment,
     Comment,
+    // The #! line at the top of a script. Trivia, like a comment.
+    Shebang,
     Word,
     NewlineAndIndent,
     OpenQuote,
@@ -52,9 +427,88 @@ pub enum TokenTag {
     InterpolatedCode,
     CloseInterpolation,
     CloseQuote,
+    Regex,
+    OpenRegex,
+    RegexFragment,
+    CloseRegex,
+    // A char the lexer couldn't make anything of. Only ever there when LexerOptions::recover is on.
+    Error,
+    // JSX (CSX, in CoffeeScript 2). A tag is JsxTagOpen (< or </), a JsxTagName (none for a <> fragment), any
+    //   number of JsxAttribute, Operator (the =) and JsxAttributeValue (a quoted string) or {expression}, then
+    //   JsxTagClose (> or />).
+    JsxTagOpen,
+    JsxTagName,
+    JsxAttribute,
+    JsxAttributeValue,
+    JsxTagClose,
+    // Text between tags, broken at newlines like string fragments.
+    JsxText,
+    // The { and } around code in an attribute or between tags.
+    OpenJsxExpression,
+    CloseJsxExpression,
+    // Whatever a user-registered rule says it is (see the rules module). The id is up to them, up to
+    //   TokenTag::MAX_CUSTOM_ID.
+    Custom(u8),
 }
 
 impl TokenTag {
+    // Every built-in tag, in declaration order.
+    pub const ALL: [TokenTag; 27] = [
+        Number,
+        Whitespace,
+        Operator,
+        Herecomment,
+        Comment,
+        Shebang,
+        Word,
+        NewlineAndIndent,
+        OpenQuote,
+        StringFragment,
+        OpenInterpolation,
+        InterpolatedCode,
+        CloseInterpolation,
+        CloseQuote,
+        Regex,
+        OpenRegex,
+        RegexFragment,
+        CloseRegex,
+        Error,
+        JsxTagOpen,
+        JsxTagName,
+        JsxAttribute,
+        JsxAttributeValue,
+        JsxTagClose,
+        JsxText,
+        OpenJsxExpression,
+        CloseJsxExpression,
+    ];
+
+    const CUSTOM_BASE: u8 = 32;
+
+    // The biggest id a Custom tag can have and still fit in a byte.
+    pub const MAX_CUSTOM_ID: u8 = u8::MAX - TokenTag::CUSTOM_BASE;
+
+    // A byte for every tag, for compact storage. Built-in tags are their index in ALL, custom ones come after.
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Custom(id) => TokenTag::CUSTOM_BASE.checked_add(id).unwrap_or_else(|| {
+                panic!(
+                    "Custom token ids only go up to {}, but got {}.",
+                    TokenTag::MAX_CUSTOM_ID,
+                    id
+                )
+            }),
+            tag => TokenTag::ALL.iter().position(|&t| t == tag).unwrap() as u8,
+        }
+    }
+
+    pub fn from_u8(tag: u8) -> Option<TokenTag> {
+        if tag >= TokenTag::CUSTOM_BASE {
+            return Some(Custom(tag - TokenTag::CUSTOM_BASE));
+        }
+        TokenTag::ALL.get(tag as usize).cloned()
+    }
+
     pub fn at<T>(&self, to_span: T) -> Token
     where
         T: ToSpan,
@@ -62,6 +516,7 @@ impl TokenTag {
         Token::make(*self, *to_span.to_span())
     }
 
+    #[allow(clippy::expect_fun_call)]
     pub fn assert_at<T>(&self, maybe_to_span: Option<T>) -> Token
     where
         T: ToSpan,
@@ -74,15 +529,24 @@ impl TokenTag {
             .as_str(),
         ))
     }
+
+    // Whitespace, comments and newlines. Nothing a parser needs to see, but everything a formatter must keep.
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            *self,
+            Whitespace | Comment | Herecomment | NewlineAndIndent | Shebang
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Token {
     pub tag: TokenTag,
     pub span: Span,
 }
 
 impl Token {
+    #[allow(clippy::redundant_field_names)]
     pub fn make(tag: TokenTag, span: Span) -> Token {
         Token {
             tag: tag,
@@ -90,14 +554,45 @@ impl Token {
         }
     }
 
+    #[allow(clippy::needless_lifetimes, clippy::to_string_in_format_args)]
     pub fn text<'t, TSource>(&self, code: &'t TSource) -> String
     where
         TSource: SourceCodeProvider,
     {
         format!("[{:?} {}]", self.tag, get_region(code, self).to_string())
     }
+
+    // What a herecomment says, without the ### on either end, for passing through to the generated JS. Like the
+    //   reference lexer, the indentation of the block it sits in is taken off every line after the first (we use the
+    //   indentation of the line the comment starts on). None if this isn't a herecomment.
+    pub fn herecomment_body<TSource>(&self, code: &TSource) -> Option<String>
+    where
+        TSource: SourceCodeProvider,
+    {
+        if self.tag != Herecomment {
+            return None;
+        }
+        let source = code.get_source_code();
+        let text = get_region(code, self);
+        let body = &text[3..];
+        // No closing ### if it ran into EOF.
+        let body = body.strip_suffix("###").unwrap_or(body);
+
+        let start = self.span.start_pos.index;
+        let line_start = source[..start].rfind(['\n', '\r']).map_or(0, |i| i + 1);
+        let line = &source[line_start..start];
+        let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+        if indent.is_empty() {
+            return Some(body.to_string());
+        }
+        Some(
+            body.replace(&format!("\n{}", indent), "\n")
+                .replace(&format!("\r{}", indent), "\r"),
+        )
+    }
 }
 
+#[allow(clippy::needless_lifetimes)]
 pub trait SourceCodeProvider {
     fn get_source_code<'s>(&'s self) -> &'s str;
 }
@@ -108,21 +603,26 @@ impl ToSpan for Token {
     }
 }
 
+#[allow(clippy::needless_lifetimes, clippy::explicit_auto_deref)]
 impl<'s> SourceCodeProvider for &'s str {
     fn get_source_code<'x>(&'x self) -> &'x str {
         *self
     }
 }
 
+// Only a chomper over a &str has all the code to hand out.
+#[allow(clippy::needless_lifetimes)]
 impl<'z> SourceCodeProvider for Chomper<'z> {
     fn get_source_code<'s>(&'s self) -> &'s str {
-        self.code
+        self.code()
+            .expect("A chomper reading a stream doesn't keep all the code.")
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'l> SourceCodeProvider for Lexer<'l> {
     fn get_source_code<'s>(&'s self) -> &'s str {
-        self.chomper.code
+        self.chomper.get_source_code()
     }
 }
 
@@ -142,6 +642,7 @@ pub trait FullSource {
         TSource: SourceCodeProvider;
 }
 
+#[allow(clippy::needless_lifetimes)]
 impl<'coolness, T> FullSource for &'coolness T
 where
     T: SourceCodeProvider,
@@ -156,237 +657,775 @@ where
     }
 }
 
+// Single chars some of the simpler tokens are made of. A line break is never SPACE; it gets a token of its own.
+const SPACE: Pattern<'static> = Class(|c| c.is_whitespace() && c != '\n');
+const DIGIT: Pattern<'static> = Class(|c| c.is_ascii_digit());
+const PLUS_OR_MINUS: Pattern<'static> = Class(|c| c == '+' || c == '-');
+const REGEX_FLAGS: Pattern<'static> = zero_or_more(&Class(|c| c.is_ascii_lowercase()));
+
 impl<'li> Lexer<'li> {
     pub fn new(code: &'li str) -> Lexer<'li> {
-        Lexer {
-            chomper: Chomper::new(code),
+        Lexer::with_options(code, &LexerOptions::default())
+    }
+
+    // For Literate CoffeeScript (.litcoffee). Prose comes out as Comment tokens and only the code blocks are really
+    //   lexed. Spans still point into code as given, so positions match the .litcoffee file.
+    pub fn literate(code: &'li str) -> Lexer<'li> {
+        Lexer::with_options(
+            code,
+            &LexerOptions {
+                literate: true,
+                ..LexerOptions::default()
+            },
+        )
+    }
+
+    pub fn with_options(code: &'li str, options: &LexerOptions) -> Lexer<'li> {
+        let state = if options.literate {
+            LexerState::start_literate(code)
+        } else {
+            LexerState::start(code)
+        };
+        Lexer::resume_with_options(code, &state, options)
+    }
+
+    // Lexes what source reads, a chunk at a time, without holding all of it (see the reader_source module). Spans
+    //   are byte offsets into the whole input, same as lexing it as a &str. Nothing hands out the whole code from a
+    //   streaming source, so get a token's text with chomper().value as you go, or read it back from the input.
+    pub fn with_source(source: ReaderSource<'li>, options: &LexerOptions) -> Lexer<'li> {
+        let mut chomper = Chomper::with_reader(source);
+        let mut state = LexerState::at(chomper.position());
+        if options.literate {
+            state.literate = Some(LiterateState::TOP.next_line(chomper.rest_of_line()));
         }
+        Lexer::with_chomper(chomper, &state, options)
     }
 
-    pub fn lex(&mut self) -> Vec<Token> {
-        let mut tokens: Vec<Token> = vec![];
+    // with_source, reading reader in chunks of the default size.
+    pub fn from_reader<R: Read + 'li>(reader: R, options: &LexerOptions) -> Lexer<'li> {
+        Lexer::with_source(ReaderSource::new(reader), options)
+    }
 
-        loop {
-            if self.chomper.is_eof {
-                break;
-            }
-            match self.chomper.peek() {
-                None => break,
-                Some(c) => {
-                    let token = match c {
-                        ch if Lexer::is_valid_first_char_of_word(ch) => self.get_word(),
-                        '\n' => self.process_newline(),
-                        '\"' => self.process_double_quote(&mut tokens),
-                        ws if ws.is_whitespace() => self.get_whitespace(),
-                        num if num.is_digit(10) => self.get_number(),
-                        '+' | '-' => self.get_operator(),
-                        '#' => self.get_comment(),
-                        _ => {panic!("Charlie, you have not implemented ability to match char {} at index {}", c, self.chomper.index)}
-                    };
-
-                    println!("Got token!! {:?}", token);
-                    println!("Chomper peek char is {:?}", self.chomper.peek());
-                    println!("At this point, index is {:?}", self.chomper.index);
-
-                    if token.is_some() {
-                        tokens.push(token.unwrap())
-                    };
-                }
-            }
+    pub fn with_limits(code: &'li str, limits: LexLimits) -> Lexer<'li> {
+        let mut lexer = Lexer::new(code);
+        lexer.set_limits(limits);
+        lexer
+    }
+
+    pub fn set_limits(&mut self, limits: LexLimits) {
+        self.options.limits = limits;
+    }
+
+    pub fn options(&self) -> &LexerOptions {
+        &self.options
+    }
+
+    // The errors lexing got past, with LexerOptions::recover on. Empty otherwise; the first error ends the lex.
+    pub fn errors(&self) -> &[LexError] {
+        &self.errors
+    }
+
+    // Swaps in a different set of rules for plain code, usually the built-in ones plus a few of your own.
+    pub fn set_rules(&mut self, rules: &LexRules) {
+        self.rules = rules.clone();
+    }
+
+    // For scan fns in user rules to chomp with.
+    pub fn chomper(&mut self) -> &mut Chomper<'li> {
+        &mut self.chomper
+    }
+
+    // Every span from here on says it's from file. SourceFile::lexer does this for you.
+    pub fn set_file(&mut self, file: FileId) {
+        self.chomper.file = file;
+    }
+
+    // Picks up lexing code from a state captured earlier. The code only has to match the original from
+    //   state.position onwards.
+    pub fn resume(code: &'li str, state: &LexerState) -> Lexer<'li> {
+        Lexer::resume_with_options(code, state, &LexerOptions::default())
+    }
+
+    // options.literate doesn't matter here; the state already says whether it's literate.
+    pub fn resume_with_options(
+        code: &'li str,
+        state: &LexerState,
+        options: &LexerOptions,
+    ) -> Lexer<'li> {
+        Lexer::with_chomper(Chomper::starting_at(code, state.position), state, options)
+    }
+
+    fn with_chomper(
+        chomper: Chomper<'li>,
+        state: &LexerState,
+        options: &LexerOptions,
+    ) -> Lexer<'li> {
+        Lexer {
+            previous_token_start: chomper.index,
+            chomper,
+            modes: state.modes.clone(),
+            last_significant: state.last_significant,
+            interpolation_depth: state
+                .modes
+                .iter()
+                .filter(|&&m| m == LexMode::Interpolation)
+                .count(),
+            indents: state.indents.clone(),
+            literate: state.literate,
+            indent_style: state.indent_style,
+            last_indentation: state.last_indentation.clone(),
+            diagnostics: vec![],
+            options: *options,
+            errors: vec![],
+            rules: LexRules::builtin(),
+            token_count: state.token_count,
+            failed: false,
         }
+    }
 
-        tokens
+    // Valid between any two tokens.
+    pub fn state(&self) -> LexerState {
+        LexerState {
+            position: self.chomper.position(),
+            modes: self.modes.clone(),
+            last_significant: self.last_significant,
+            indents: self.indents.clone(),
+            literate: self.literate,
+            indent_style: self.indent_style,
+            last_indentation: self.last_indentation.clone(),
+            token_count: self.token_count,
+        }
     }
 
-    pub fn process_double_quote(&mut self, token_list: &mut Vec<Token>) -> Option<Token> {
-        inside_open_quote(self, token_list);
-        return None;
+    // The state to save for the line after this one, if the lexer is sitting on the newline that ends a line. Every
+    //   newline is a token boundary except the ones inside a herecomment, so those are the only lines you won't get
+    //   a state for.
+    pub fn line_state(&self) -> Option<LexerState> {
+        if self.failed || self.chomper.peek() != Some('\n') {
+            return None;
+        }
+        Some(self.state())
+    }
 
-        fn inside_open_quote(lexer: &mut Lexer, token_list: &mut Vec<Token>) {
-            let open_quote_cr = lexer.chomper.expect("\"");
-            token_list.push(OpenQuote.at(open_quote_cr));
+    pub fn mode(&self) -> LexMode {
+        *self.modes.last().unwrap()
+    }
 
-            // todo charlie, clearly there is duplication here too! Come back to it down the road.
+    // Lexes everything up front. Panics on the first error; use try_lex (or iterate) to get the error instead.
+    pub fn lex(&mut self) -> Vec<Token> {
+        match self.try_lex() {
+            Ok(tokens) => tokens,
+            Err(e) => panic!("Lexing failed: {}", e),
+        }
+    }
 
-            loop {
-                // let string_frag_cr = lexer.chomper.chomp_till_str(|s| s.starts_with("\"") || s.starts_with("#{"));
-                let string_frag_cr = lexer.chomper.chomp_till_str_with_previous(|str, pc| {
-                    (str.starts_with("\"") && pc != Some('\\')) || str.starts_with("#{")
-                });
-                if lexer.chomper.is_eof {
-                    panic!("Hit eof while parsing an interpolated string.")
-                }
-                if string_frag_cr.is_some() {
-                    token_list.push(StringFragment.at(string_frag_cr.unwrap()));
-                }
+    pub fn try_lex(&mut self) -> Result<Vec<Token>, LexError> {
+        self.by_ref().collect()
+    }
 
-                match lexer.chomper.peek().unwrap() {
-                    '\"' => {
-                        token_list.push(CloseQuote.at(lexer.chomper.expect("\"")));
-                        return;
-                    }
-                    '#' => inside_open_interpolation(lexer, token_list),
-                    unexpected_str => panic!("Got unexpected char: {}", unexpected_str),
+    fn next_token(&mut self) -> Result<Option<Token>, LexError> {
+        if self.chomper.len_read() > self.options.limits.max_input_size {
+            return Err(self.limit_exceeded(Limit::InputSize, self.options.limits.max_input_size));
+        }
+        loop {
+            if self.chomper.is_eof || self.chomper.peek().is_none() {
+                if let Some(e) = self.chomper.read_error() {
+                    return Err(self.error(LexErrorKind::Unreadable(e.kind())));
+                }
+                let kind = match self.mode() {
+                    LexMode::Code => return Ok(None),
+                    LexMode::DoubleString | LexMode::Heredoc => LexErrorKind::UnterminatedString,
+                    LexMode::Interpolation => LexErrorKind::UnterminatedInterpolation,
+                    LexMode::Regex => LexErrorKind::UnterminatedRegex,
+                    LexMode::JsxTag
+                    | LexMode::JsxClosingTag
+                    | LexMode::JsxChildren
+                    | LexMode::JsxExpression
+                    | LexMode::Braces => LexErrorKind::UnterminatedJsx,
                 };
+                // Recovering, there's nothing left to recover, so close everything and stop.
+                self.modes.truncate(1);
+                let error = self.error(kind);
+                self.report(error)?;
+                return Ok(None);
             }
-        }
 
-        fn inside_open_interpolation(lexer: &mut Lexer, token_list: &mut Vec<Token>) {
-            let open_cr = lexer.chomper.expect("#{");
-            token_list.push(OpenInterpolation.at(open_cr));
+            if self.token_count >= self.options.limits.max_token_count {
+                return Err(
+                    self.limit_exceeded(Limit::TokenCount, self.options.limits.max_token_count)
+                );
+            }
 
-            loop {
-                let code_frag_cr = lexer.chomper.chomp(|c| c == '}' || c == '\"');
-                if lexer.chomper.is_eof {
-                    panic!("Hit eof while parsing some interpolation code inside an interpolated string.")
+            self.chomper.forget_before(self.previous_token_start);
+            self.previous_token_start = self.chomper.index;
+            let token = match self.mode() {
+                LexMode::Code => self.lex_code()?,
+                LexMode::DoubleString => {
+                    self.lex_inside_string("\"", StringFragment, CloseQuote)?
                 }
-                if code_frag_cr.is_some() {
-                    token_list.push(InterpolatedCode.at(code_frag_cr.unwrap()));
-                }
-
-                match lexer.chomper.peek().unwrap() {
-                    '}' => {
-                        token_list.push(CloseInterpolation.at(lexer.chomper.expect("}")));
-                        return;
+                LexMode::Heredoc => self.lex_inside_string("\"\"\"", StringFragment, CloseQuote)?,
+                LexMode::Regex => self.lex_inside_string("///", RegexFragment, CloseRegex)?,
+                LexMode::Interpolation => self.lex_interpolation(),
+                LexMode::JsxTag | LexMode::JsxClosingTag => self.lex_jsx_tag()?,
+                LexMode::JsxChildren => self.lex_jsx_children(),
+                LexMode::JsxExpression | LexMode::Braces => self.lex_jsx_expression()?,
+            };
+
+            if let Some(t) = token {
+                if t.tag == Number && self.options.strict_octal {
+                    let digits = self.chomper.value(&t);
+                    if digits.len() > 1 && digits.starts_with('0') {
+                        self.report(LexError::new(LexErrorKind::LeadingZero, t.span.start_pos))?;
                     }
-                    '\"' => inside_open_quote(lexer, token_list),
-                    _ => unreachable!(),
-                };
+                }
+                self.token_count += 1;
+                if !t.tag.is_trivia() {
+                    self.last_significant = Some(t.tag);
+                }
+                if t.tag == NewlineAndIndent {
+                    self.track_indent(&t)?;
+                }
+                return Ok(token);
             }
         }
     }
 
-    pub fn get_word(&mut self) -> Option<Token> {
-        fn fail(msg: String) {
-            panic!("You called get_word, but the next char {}", msg);
-        }
-        match self.chomper.peek() {
-            None => fail(String::from("is the end of file.")),
-            Some(ch) => {
-                if !Lexer::is_valid_first_char_of_word(ch) {
-                    fail(format!(
-                        "is not a valid first char for a word. Char is {}",
-                        ch
-                    ));
-                }
-            }
-        };
+    fn error(&self, kind: LexErrorKind) -> LexError {
+        LexError::new(kind, self.chomper.position())
+    }
 
-        let first = self.chomper.chomp_count(1).unwrap();
-        let rest = self
-            .chomper
-            .chomp(|c| !Lexer::is_valid_subsequent_char_of_word(c));
-        let span = (first + rest).span;
+    // An error that recovery can get past: kept if we're recovering, returned if not.
+    fn report(&mut self, error: LexError) -> Result<(), LexError> {
+        if !self.options.recover {
+            return Err(error);
+        }
+        self.errors.push(error);
+        Ok(())
+    }
 
-        Some(Word.at(span))
+    fn limit_exceeded(&self, limit: Limit, max: usize) -> LexError {
+        self.error(LexErrorKind::LimitExceeded(limit, max))
     }
 
-    fn is_valid_first_char_of_word(ch: char) -> bool {
-        let result = match ch {
-            '$' | '_' => true,
-            'A'..='Z' => true,
-            'a'..='z' => true,
-            _ => false, // todo intentionally only allowing identifiers and words to start with "normal" ascii chars for now. Later, add support
-                        //   for higher ascii and unicode (/x7f - /uffff, as the reference coffeescript compiler does)
+    // Opens or closes indentation levels for the line that newline_token starts. Blank and comment-only lines don't
+    //   count, same as in the reference lexer.
+    fn track_indent(&mut self, newline_token: &Token) -> Result<(), LexError> {
+        let newline_and_indent = self.chomper.value(newline_token);
+        let indentation =
+            match indentation_in(newline_and_indent, self.chomper.peek(), self.literate) {
+                None => return Ok(()),
+                Some(indentation) => indentation,
+            };
+        let newline = newline_token.span.start_pos;
+        let line_start = Position {
+            index: newline.index + newline_len(newline_and_indent),
+            line_no: newline.line_no + 1,
+            col_no: 0,
         };
+        if let Some(kind) = check_indentation(
+            &mut self.indent_style,
+            &mut self.last_indentation,
+            indentation,
+        ) {
+            self.diagnostics.push(IndentDiagnostic {
+                kind,
+                position: line_start,
+            });
+        }
+        let tabbed = indentation.contains('\t');
+        let width = indentation.chars().count();
+        if !self.options.allow_tabs && tabbed {
+            self.report(LexError::new(LexErrorKind::TabIndentation, line_start))?;
+        }
 
-        // println!("Was {:?} a valid first char of word? ... {:?}", ch, result);
+        if width > *self.indents.last().unwrap_or(&0)
+            && self.indents.len() >= self.options.limits.max_indent_depth
+        {
+            return Err(
+                self.limit_exceeded(Limit::IndentDepth, self.options.limits.max_indent_depth)
+            );
+        }
+        indent_to(&mut self.indents, width);
+        Ok(())
+    }
 
-        result
+    // How many indentation levels are open at this point.
+    pub fn indent_depth(&self) -> usize {
+        self.indents.len()
     }
 
-    fn is_valid_subsequent_char_of_word(ch: char) -> bool {
-        match ch {
-            '$' | '_' => true,
-            'a'..='z' => true,
-            'A'..='Z' => true,
-            '0'..='9' => true,
-            _ => false,
-        }
+    // Tabs or spaces, going by the first indented line. None until there's been one.
+    pub fn indent_style(&self) -> Option<IndentStyle> {
+        self.indent_style
     }
 
-    pub fn get_whitespace(&mut self) -> Option<Token> {
-        // todo, ONLY pub so you can test it, fix that later
-        Some(Whitespace.assert_at(self.chomper.chomp(|ch| !ch.is_whitespace() || ch == '\n')))
-        // todo the wrong thing here is that the token Whitespace and the fn (|ch| ! ch.is_whitespace()) truly belong together. I'm repeating myself by saying that twice in this call
-        // The answer is not necessarily the OO answer ... bundle it into the struct. Anything that associates the TokenTag with the scan fn makes sense, so think outside the oo box.
+    // Indentation problems found so far. They don't stop the lex.
+    pub fn diagnostics(&self) -> &[IndentDiagnostic] {
+        &self.diagnostics
     }
 
-    pub fn process_newline(&mut self) -> Option<Token> {
-        Some(
-            NewlineAndIndent
-                .at(self.chomper.expect("\n")
-                    + self.chomper.chomp(|c| c == '\n' || !c.is_whitespace())),
-        )
+    fn lex_code(&mut self) -> Result<Option<Token>, LexError> {
+        if self.chomper.index == self.chomper.start() && self.chomper.text().starts_with("#!") {
+            return Ok(self.get_shebang());
+        }
+        if self.literate.is_some_and(|l| l.in_prose) {
+            return Ok(self.get_prose());
+        }
+        let c = self.chomper.peek().unwrap();
+        let start = self.chomper.index;
+        for i in 0..self.rules.rules().len() {
+            let rule = self.rules.rules()[i];
+            if !(rule.first_char)(c) {
+                continue;
+            }
+            let token = (rule.scan)(self);
+            if token.is_some() || self.chomper.index != start {
+                return Ok(token);
+            }
+        }
+        self.unexpected_char(c)
     }
 
-    pub fn get_number(&mut self) -> Option<Token> {
-        let result = Some(Number.assert_at(self.chomper.chomp(|c| !c.is_digit(10))));
-        println!("Result of get_number is {:?}", result);
-        result
+    // With recovery on, the char becomes an Error token and lexing goes on after it.
+    fn unexpected_char(&mut self, c: char) -> Result<Option<Token>, LexError> {
+        let error = self.error(LexErrorKind::UnexpectedChar(c));
+        self.report(error)?;
+        Ok(Some(Error.assert_at(self.chomper.chomp_count(1))))
     }
 
-    pub fn get_operator(&mut self) -> Option<Token> {
-        Some(Operator.assert_at(self.chomper.chomp(|c| c != '+' && c != '-')))
+    // Opens a string. Everything up to the matching close quote comes out of lex_inside_string, one token at a time.
+    pub fn process_double_quote(&mut self) -> Option<Token> {
+        if self.chomper.text().starts_with("\"\"\"") {
+            self.modes.push(LexMode::Heredoc);
+            return Some(OpenQuote.at(self.chomper.expect("\"\"\"")));
+        }
+        self.modes.push(LexMode::DoubleString);
+        Some(OpenQuote.at(self.chomper.expect("\"")))
     }
 
-    pub fn get_comment(&mut self) -> Option<Token> {
-        // todo next line can be nicer
-        if self.chomper.peek() != Some('#') {
-            panic!(
-                "I thought I was parsing a comment, but it starts with this: {:?}",
-                self.chomper.peek()
-            )
+    // One token from inside a string, heredoc or heregex: either the closer, the start of an interpolation, or a
+    //   fragment of literal text. Fragments are broken at newlines so that every line start is a token boundary.
+    fn lex_inside_string(
+        &mut self,
+        closer: &'static str,
+        fragment_tag: TokenTag,
+        close_tag: TokenTag,
+    ) -> Result<Option<Token>, LexError> {
+        let text = self.chomper.text();
+        if text.starts_with(closer) {
+            self.modes.pop();
+            let mut span = self.chomper.expect(closer).span;
+            if close_tag == CloseRegex {
+                if let Some(flags) = self.chomper.chomp_pattern(&REGEX_FLAGS) {
+                    span = span.cover(&flags.span);
+                }
+            }
+            return Ok(Some(close_tag.at(span)));
         }
-        println!("seeing if we have herecomment");
+        if text.starts_with("#{") {
+            if self.interpolation_depth >= self.options.limits.max_interpolation_depth {
+                return Err(self.limit_exceeded(
+                    Limit::InterpolationDepth,
+                    self.options.limits.max_interpolation_depth,
+                ));
+            }
+            self.modes.push(LexMode::Interpolation);
+            self.interpolation_depth += 1;
+            return Ok(Some(OpenInterpolation.at(self.chomper.expect("#{"))));
+        }
+
+        // A fragment can start with a line break, but it stops before the next one. A backslash escapes whatever's
+        //   after it, including another backslash and the #{ of an interpolation.
+        let stop = [Literal(closer), Literal("#{"), NEWLINE];
+        let fragment = Seq(&[
+            Optional(&NEWLINE),
+            Until {
+                stop: &Alt(&stop),
+                escape: Some('\\'),
+            },
+        ]);
+        let fragment_cr = self.chomper.chomp_pattern(&fragment);
+        Ok(Some(fragment_tag.assert_at(fragment_cr)))
+    }
 
-        match &self.chomper.text()[0..3] {
-            // todo can probably pattern match more gracefully here
-            "###" => self.get_here_comment(),
+    fn lex_interpolation(&mut self) -> Option<Token> {
+        match self.chomper.peek() {
+            Some('}') => {
+                self.modes.pop();
+                self.interpolation_depth -= 1;
+                Some(CloseInterpolation.at(self.chomper.expect("}")))
+            }
+            Some('\"') => self.process_double_quote(),
             _ => {
-                println!("in get_comment, and decided it was NOT a herecomment.");
-                println!("text is: {}", self.chomper.text());
-                // trace_macros!(true)
-                crf!(self.chomper.text());
-                // trace_macros!(false)
-                Some(Comment.assert_at(self.chomper.chomp(|c| c == '\n')))
+                let code_frag_cr = self.chomper.chomp_and_see_previous(|c, prev| {
+                    c == '}' || c == '\"' || (c == '\n' && prev.is_some())
+                });
+                Some(InterpolatedCode.assert_at(code_frag_cr))
             }
         }
     }
 
-    pub fn get_here_comment(&mut self) -> Option<Token> {
-        let delimiter = self.chomper.expect("###");
-        if delimiter.hit_eof {
-            return Some(Herecomment.at(delimiter));
+    // A / is a heregex (///), a regex, or division, in that order of preference. Whether a regex is even possible
+    //   depends on what came before: after something that has a value, it's division.
+    pub fn process_slash(&mut self) -> Option<Token> {
+        if self.chomper.text().starts_with("///") {
+            self.modes.push(LexMode::Regex);
+            return Some(OpenRegex.at(self.chomper.expect("///")));
         }
-        let mut cr = self
-            .chomper
-            .chomp_till_str(|str| str.starts_with("###"))
-            .unwrap();
 
-        println!("--------------------");
-
-        println!("Delimiter chomp result: {:?}", delimiter);
-        println!("Post-delim chomp result: {:?}", cr);
+        let regex_allowed = !matches!(
+            self.last_significant,
+            Some(Number)
+                | Some(Word)
+                | Some(CloseQuote)
+                | Some(Regex)
+                | Some(CloseRegex)
+                | Some(JsxTagClose)
+        );
+        if regex_allowed {
+            if let Some(length) = Lexer::regex_literal_length(self.chomper.rest_of_line()) {
+                return Some(Regex.assert_at(self.chomper.chomp_count(length)));
+            }
+        }
 
-        cr = delimiter + cr;
-        println!("Added chomp result: {:?}", cr);
+        Some(Operator.at(self.chomper.expect("/")))
+    }
 
-        println!("--------------------");
+    // How many chars of text (which starts with '/') make up a regex literal, flags included. None if the regex never
+    //   closes on this line, in which case the / wasn't a regex after all.
+    fn regex_literal_length(text: &str) -> Option<usize> {
+        let mut in_class = false;
+        let mut escaped = false;
+        for (i, c) in text.char_indices().skip(1) {
+            match c {
+                '\n' | '\r' => return None,
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '[' => in_class = true,
+                ']' => in_class = false,
+                '/' if !in_class => {
+                    if i == 1 {
+                        // "//" is an empty regex, which isn't a thing.
+                        return None;
+                    }
+                    let flags = text[i + 1..]
+                        .chars()
+                        .take_while(|f| f.is_ascii_lowercase())
+                        .count();
+                    return Some(text[..i + 1].chars().count() + flags);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
 
-        if !cr.hit_eof {
-            cr = cr + self.chomper.expect("###");
+    // A < or > in code is a comparison (or a shift), unless the < starts a JSX element. Same rule as the reference
+    //   lexer: it's JSX if a tag name or the > of a fragment comes right after the <, and it can't be the right
+    //   hand side of an unspaced comparison like a<b. So a < b and a<b compare, but a <b is JSX. Inside JSX, it's
+    //   always JSX. CoffeeScript 1 has no JSX at all.
+    pub fn process_angle_bracket(&mut self) -> Option<Token> {
+        if self.jsx_can_start_here() {
+            self.modes.push(LexMode::JsxTag);
+            return Some(JsxTagOpen.at(self.chomper.expect("<")));
         }
+        const COMPARISON: Pattern<'static> = Alt(&[
+            Literal(">>>"),
+            Literal("<<"),
+            Literal(">>"),
+            Literal("<="),
+            Literal(">="),
+            Literal("<"),
+            Literal(">"),
+        ]);
+        Some(Operator.assert_at(self.chomper.chomp_pattern(&COMPARISON)))
+    }
 
-        Some(Herecomment.at(cr))
+    fn jsx_can_start_here(&self) -> bool {
+        let text = self.chomper.text();
+        if self.options.dialect != Dialect::CoffeeScript2 || !text.starts_with('<') {
+            return false;
+        }
+        let opens_tag = text[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c == '>' || Lexer::is_valid_first_char_of_word(c));
+        let spaced = self
+            .chomper
+            .previous_char()
+            .is_none_or(|c| c.is_whitespace());
+        let comparable = matches!(self.last_significant, Some(Word) | Some(Number));
+        opens_tag && (self.in_jsx() || spaced || !comparable)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{get_region, FullSource, Lexer, Number, Token, Whitespace};
-    use chomp::{ChompResult, Chomper, Position, Span};
-    // not yet tested: SourceCodeProvider, TokenTag, Operator,
+    // Somewhere inside an element, even if it's in the code of one of its {expressions}.
+    fn in_jsx(&self) -> bool {
+        self.modes
+            .iter()
+            .any(|&m| m == LexMode::JsxChildren || m == LexMode::JsxExpression)
+    }
+
+    // One token from inside a JSX opening or closing tag.
+    fn lex_jsx_tag(&mut self) -> Result<Option<Token>, LexError> {
+        const TAG_SPACE: Pattern<'static> = Seq(&[Optional(&NEWLINE), zero_or_more(&SPACE)]);
+        // Names can have dashes (data-id), and namespaces (svg:rect). Tag names can be dotted too (Foo.Bar).
+        const NAME: Pattern<'static> = Seq(&[
+            Class(Lexer::is_valid_first_char_of_word),
+            zero_or_more(&Class(|c| {
+                Lexer::is_valid_subsequent_char_of_word(c) || c == '-' || c == ':' || c == '.'
+            })),
+        ]);
+        let c = self.chomper.peek().unwrap();
+        let in_opening_tag = self.mode() == LexMode::JsxTag;
+        if c.is_whitespace() {
+            return Ok(Some(
+                Whitespace.assert_at(self.chomper.chomp_pattern(&TAG_SPACE)),
+            ));
+        }
+        if in_opening_tag && self.chomper.text().starts_with("/>") {
+            self.modes.pop();
+            return Ok(Some(JsxTagClose.at(self.chomper.expect("/>"))));
+        }
+        match c {
+            '>' => {
+                self.modes.pop();
+                if in_opening_tag {
+                    self.modes.push(LexMode::JsxChildren);
+                }
+                Ok(Some(JsxTagClose.at(self.chomper.expect(">"))))
+            }
+            '{' if in_opening_tag => {
+                self.modes.push(LexMode::JsxExpression);
+                Ok(Some(OpenJsxExpression.at(self.chomper.expect("{"))))
+            }
+            '=' => Ok(Some(Operator.at(self.chomper.expect("=")))),
+            '"' => self.get_jsx_attribute_value("\""),
+            '\'' => self.get_jsx_attribute_value("'"),
+            _ if Lexer::is_valid_first_char_of_word(c) => {
+                let tag = if self.last_significant == Some(JsxTagOpen) {
+                    JsxTagName
+                } else {
+                    JsxAttribute
+                };
+                Ok(Some(tag.assert_at(self.chomper.chomp_pattern(&NAME))))
+            }
+            _ => self.unexpected_char(c),
+        }
+    }
+
+    // A quoted attribute value. No escapes and no interpolation, same as in JSX, and it has to close on the line
+    //   it opens on.
+    fn get_jsx_attribute_value(&mut self, quote: &'static str) -> Result<Option<Token>, LexError> {
+        let stop = [Literal(quote), NEWLINE];
+        let value = Seq(&[
+            Literal(quote),
+            Until {
+                stop: &Alt(&stop),
+                escape: None,
+            },
+        ]);
+        let mut span = self.chomper.chomp_pattern(&value).unwrap().span;
+        if self.chomper.text().starts_with(quote) {
+            span = span.cover(&self.chomper.expect(quote).span);
+        } else {
+            let error = self.error(LexErrorKind::UnterminatedString);
+            self.report(error)?;
+        }
+        Ok(Some(JsxAttributeValue.at(span)))
+    }
+
+    // One token from between an element's tags.
+    fn lex_jsx_children(&mut self) -> Option<Token> {
+        let text = self.chomper.text();
+        if text.starts_with("</") {
+            self.modes.pop();
+            self.modes.push(LexMode::JsxClosingTag);
+            return Some(JsxTagOpen.at(self.chomper.expect("</")));
+        }
+        if text.starts_with('<') {
+            self.modes.push(LexMode::JsxTag);
+            return Some(JsxTagOpen.at(self.chomper.expect("<")));
+        }
+        if text.starts_with('{') {
+            self.modes.push(LexMode::JsxExpression);
+            return Some(OpenJsxExpression.at(self.chomper.expect("{")));
+        }
+        // Like a string fragment, text stops before the next line break so every line start is a token boundary.
+        let stop = [Literal("<"), Literal("{"), NEWLINE];
+        let text = Seq(&[
+            Optional(&NEWLINE),
+            Until {
+                stop: &Alt(&stop),
+                escape: None,
+            },
+        ]);
+        let text_cr = self.chomper.chomp_pattern(&text);
+        Some(JsxText.assert_at(text_cr))
+    }
+
+    // Plain code, except for the braces: a { opens a nested pair, and the } that matches the opening one closes the
+    //   expression.
+    fn lex_jsx_expression(&mut self) -> Result<Option<Token>, LexError> {
+        match self.chomper.peek() {
+            Some('}') => {
+                let tag = match self.modes.pop() {
+                    Some(LexMode::Braces) => Operator,
+                    _ => CloseJsxExpression,
+                };
+                Ok(Some(tag.at(self.chomper.expect("}"))))
+            }
+            Some('{') => {
+                self.modes.push(LexMode::Braces);
+                Ok(Some(Operator.at(self.chomper.expect("{"))))
+            }
+            _ => self.lex_code(),
+        }
+    }
+
+    pub fn get_word(&mut self) -> Option<Token> {
+        const WORD: Pattern<'static> = Seq(&[
+            Class(Lexer::is_valid_first_char_of_word),
+            Repeat {
+                pattern: &Class(Lexer::is_valid_subsequent_char_of_word),
+                min: 0,
+                max: None,
+            },
+        ]);
+        match self.chomper.chomp_pattern(&WORD) {
+            Some(cr) => Some(Word.at(cr)),
+            None => panic!(
+                "You called get_word, but the next char is not a valid first char for a word. Char is {:?}",
+                self.chomper.peek()
+            ),
+        }
+    }
+
+    pub fn is_valid_first_char_of_word(ch: char) -> bool {
+        let result = match ch {
+            '$' | '_' => true,
+            'A'..='Z' => true,
+            'a'..='z' => true,
+            _ => false, // todo intentionally only allowing identifiers and words to start with "normal" ascii chars for now. Later, add support
+                        //   for higher ascii and unicode (/x7f - /uffff, as the reference coffeescript compiler does)
+        };
+
+        // println!("Was {:?} a valid first char of word? ... {:?}", ch, result);
+
+        result
+    }
+
+    #[allow(clippy::match_like_matches_macro)]
+    fn is_valid_subsequent_char_of_word(ch: char) -> bool {
+        match ch {
+            '$' | '_' => true,
+            'a'..='z' => true,
+            'A'..='Z' => true,
+            '0'..='9' => true,
+            _ => false,
+        }
+    }
+
+    pub fn get_whitespace(&mut self) -> Option<Token> {
+        Some(Whitespace.assert_at(self.chomper.chomp_pattern(&one_or_more(&SPACE))))
+    }
+
+    pub fn process_newline(&mut self) -> Option<Token> {
+        let newline = self.chomper.expect("\n");
+        if let Some(literate) = self.literate {
+            self.literate = Some(literate.next_line(self.chomper.rest_of_line()));
+        }
+        let indent = self.chomper.chomp(|c| c == '\n' || !c.is_whitespace());
+        Some(NewlineAndIndent.at(indent.map_or(newline.span, |i| newline.span.cover(&i.span))))
+    }
+
+    // A #!/usr/bin/env coffee line. Only ever the very first thing in the file.
+    fn get_shebang(&mut self) -> Option<Token> {
+        self.literate = self.literate.map(LiterateState::past_prose);
+        Some(Shebang.assert_at(self.chomper.chomp_line()))
+    }
+
+    // The rest of a prose line in a .litcoffee file.
+    fn get_prose(&mut self) -> Option<Token> {
+        self.literate = self.literate.map(LiterateState::past_prose);
+        Some(Comment.assert_at(self.chomper.chomp_line()))
+    }
+
+    pub fn get_number(&mut self) -> Option<Token> {
+        Some(Number.assert_at(self.chomper.chomp_pattern(&one_or_more(&DIGIT))))
+    }
+
+    pub fn get_operator(&mut self) -> Option<Token> {
+        Some(Operator.assert_at(self.chomper.chomp_pattern(&one_or_more(&PLUS_OR_MINUS))))
+    }
+
+    pub fn get_comment(&mut self) -> Option<Token> {
+        // todo next line can be nicer
+        if self.chomper.peek() != Some('#') {
+            panic!(
+                "I thought I was parsing a comment, but it starts with this: {:?}",
+                self.chomper.peek()
+            )
+        }
+
+        // Same rule as the reference lexer: ### opens a herecomment only if the next thing isn't another #. ####
+        //   and up is just a line comment.
+        let text = self.chomper.text();
+        if text.starts_with("###") && !text[3..].starts_with('#') {
+            self.get_here_comment()
+        } else {
+            Some(Comment.assert_at(self.chomper.chomp_line()))
+        }
+    }
+
+    pub fn get_here_comment(&mut self) -> Option<Token> {
+        let delimiter = self.chomper.expect("###");
+        if delimiter.hit_eof {
+            return Some(Herecomment.at(delimiter));
+        }
+        let mut span = delimiter.span;
+        if let Some(body) = self.chomper.chomp_till_any_str(&["###"]) {
+            span = span.cover(&body.span);
+        }
+        // Unless it ran into eof, the body stopped at the closing ###.
+        if !self.chomper.is_eof {
+            span = span.cover(&self.chomper.expect("###").span);
+        }
+
+        Some(Herecomment.at(span))
+    }
+}
+
+// Tokens on demand, for streaming into a parser or filter without building the whole Vec. After an error, the
+//   iterator returns None.
+impl<'li> Iterator for Lexer<'li> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Result<Token, LexError>> {
+        if self.failed {
+            return None;
+        }
+        match self.next_token() {
+            Ok(token) => token.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        get_region, FullSource, LexErrorKind, LexLimits, LexMode, Lexer, LexerState, Limit, Number,
+        Token, Whitespace,
+    };
+    use chomp::fixtures::CORPUS;
+    use chomp::{position_after, ChompResult, Chomper, FileId, Position, Span};
+    use diff::diff_tokens;
+    use indent::{IndentDiagnosticKind, IndentStyle};
+    use options::{Dialect, LexerOptions};
+    // not yet tested: SourceCodeProvider, TokenTag, Operator,
 
     #[test]
     fn option_chomp_result_that_is_some_should_be_convertable_to_token() {
@@ -402,6 +1441,7 @@ mod test {
                     line_no: 44,
                     col_no: 44,
                 },
+                file: FileId::ANONYMOUS,
             },
             hit_eof: false,
         });
@@ -461,6 +1501,7 @@ mod test {
                 "[Operator +]",
                 "[Whitespace  ]",
                 "[Number 2]",
+                "[NewlineAndIndent \n]",
             ],
         );
     }
@@ -482,12 +1523,9 @@ mod test {
             actual_tokens
         );
 
-        let mut index = 0;
-        for expect in expectations.iter() {
-            let token = &actual_tokens[index];
-            let token_text = token.text(code);
-            assert_eq!(token_text, expect.to_string());
-            index = index + 1;
+        let diff = diff_tokens(&expectations, actual_tokens, code);
+        if !diff.is_match() {
+            panic!("Tokens did not match:\n{}", diff);
         }
     }
 
@@ -577,6 +1615,29 @@ the proper ending delimiter is encountered. ###"#;
                                                "[Herecomment ### This whole thing right here is a\nherecomment that can span\nmany lines. A # in the middle is no problem. It won't end until\nthe proper ending delimiter is encountered. ###]"]);
     }
 
+    #[test]
+    fn every_token_should_be_where_counting_char_by_char_puts_it() {
+        let long = format!(
+            "###\n{}###\n\"{}\"\n# {}\r\n",
+            "é herecomment\r\n".repeat(50),
+            "a \\\" string ".repeat(50),
+            "😀 comment ".repeat(50)
+        );
+        for code in CORPUS.iter().cloned().chain(Some(&long[..])) {
+            if let Ok(tokens) = Lexer::new(code).try_lex() {
+                for t in tokens.iter() {
+                    let start =
+                        position_after(code, Position::start_of(code), t.span.start_pos.index);
+                    assert_eq!(t.span.start_pos, start, "{:?}", code);
+                    assert_eq!(
+                        t.span.end_pos,
+                        position_after(code, start, t.span.end_pos.index)
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn should_handle_herecomments_that_hit_eof() {
         let code = r#"
@@ -590,6 +1651,66 @@ runs straight to EOF."#;
                                                "[Herecomment ### This whole thing right here is a\nherecomment that\nruns straight to EOF.]"]);
     }
 
+    #[test]
+    fn a_hash_near_eof_should_not_panic() {
+        for code in ["#", "##", "1 #", "1 ##", "a\n#"].iter() {
+            let tokens = get_lexer(code).lex();
+            assert_eq!(tokens.last().unwrap().tag, super::Comment, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn four_or_more_hashes_should_be_a_line_comment() {
+        let code = "#### not a herecomment\n######\n1 ####### x";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Comment #### not a herecomment]",
+                "[NewlineAndIndent \n]",
+                "[Comment ######]",
+                "[NewlineAndIndent \n]",
+                "[Number 1]",
+                "[Whitespace  ]",
+                "[Comment ####### x]",
+            ],
+        );
+    }
+
+    #[test]
+    fn a_herecomment_should_close_at_the_first_triple_hash() {
+        let code = "### a #### b";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec!["[Herecomment ### a ###]", "[Comment # b]"],
+        );
+    }
+
+    #[test]
+    fn herecomment_bodies_should_lose_their_delimiters_and_block_indentation() {
+        let code = "a\n  ###\n  Indented\n    more\n  ###\n  b ### one line ###\n### eof\n  stays";
+        let tokens = get_lexer(code).lex();
+        let bodies: Vec<String> = tokens
+            .iter()
+            .filter_map(|t| t.herecomment_body(&code))
+            .collect();
+        assert_eq!(
+            bodies,
+            vec!["\nIndented\n  more\n", " one line ", " eof\n  stays"]
+        );
+        assert_eq!(tokens[0].herecomment_body(&code), None);
+        assert_eq!(
+            get_lexer("###").lex()[0].herecomment_body(&"###"),
+            Some("".to_string())
+        );
+    }
+
+    #[allow(clippy::ptr_arg, clippy::assign_op_pattern)]
     fn dump_tokens_to_console(code: &Lexer, tokens: &Vec<Token>) {
         let mut index: u64 = 1;
         for t in tokens.iter() {
@@ -754,6 +1875,31 @@ runs straight to EOF."#;
         );
     }
 
+    #[test]
+    fn an_escaped_backslash_should_not_escape_the_closer() {
+        let code = r#""C:\\" + "\#{not} #{x}""#;
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[OpenQuote \"]",
+                "[StringFragment C:\\\\]",
+                "[CloseQuote \"]",
+                "[Whitespace  ]",
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[OpenQuote \"]",
+                "[StringFragment \\#{not} ]",
+                "[OpenInterpolation #{]",
+                "[InterpolatedCode x]",
+                "[CloseInterpolation }]",
+                "[CloseQuote \"]",
+            ],
+        );
+    }
+
     #[test]
     fn should_respect_escaped_quotes_in_interpolated_part_of_interpolated_strings() {
         let code = r#""The string is #{"The \"string".length} characters long""#;
@@ -797,4 +1943,632 @@ runs straight to EOF."#;
             ],
         );
     }
+
+    #[test]
+    fn iterating_should_yield_the_same_tokens_as_lex() {
+        let code = r#"40 + "a #{b} c" # done"#;
+        let from_lex = get_lexer(code).lex();
+        let from_iter: Vec<Token> = get_lexer(code).map(|t| t.unwrap()).collect();
+        assert_eq!(from_iter, from_lex);
+    }
+
+    #[test]
+    fn iterator_should_only_lex_as_far_as_you_pull() {
+        // The ? isn't lexable, but we never get that far.
+        let code = "40 + 2 ?";
+        let first_three: Vec<Token> = get_lexer(code).take(3).map(|t| t.unwrap()).collect();
+        assert_eq!(first_three.len(), 3);
+        assert_eq!(first_three[2].span.start_pos.index, 3);
+    }
+
+    #[test]
+    fn iterator_should_return_the_error_once_and_then_stop() {
+        let code = "40 ? 2";
+        let mut lexer = get_lexer(code);
+        assert!(lexer.next().unwrap().is_ok());
+        assert!(lexer.next().unwrap().is_ok());
+        let err = lexer.next().unwrap().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnexpectedChar('?'));
+        assert_eq!(err.position.index, 3);
+        assert_eq!(format!("{}", err), "unexpected character '?' at 1:3");
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn try_lex_should_report_an_unterminated_string_instead_of_panicking() {
+        let code = "x + 1\n\"never closed #{1}";
+        let err = get_lexer(code).try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedString);
+        assert_eq!(err.position.line_no, 2);
+
+        let err = get_lexer("\"open #{ 40 + 2").try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedInterpolation);
+    }
+
+    #[test]
+    fn should_lex_heredocs_with_one_fragment_per_line() {
+        let code = "\"\"\"\n  Hi \"#{name}\"\n\"\"\"";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[OpenQuote \"\"\"]",
+                "[StringFragment \n  Hi \"]",
+                "[OpenInterpolation #{]",
+                "[InterpolatedCode name]",
+                "[CloseInterpolation }]",
+                "[StringFragment \"]",
+                "[StringFragment \n]",
+                "[CloseQuote \"\"\"]",
+            ],
+        );
+    }
+
+    #[test]
+    fn should_tell_regexes_from_division_by_what_came_before() {
+        let code = "/a[/]b/gi + 4/2";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Regex /a[/]b/gi]",
+                "[Whitespace  ]",
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[Number 4]",
+                "[Operator /]",
+                "[Number 2]",
+            ],
+        );
+    }
+
+    #[test]
+    fn a_slash_that_never_closes_on_its_line_should_be_an_operator() {
+        let code = "+ /2\n/";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[Operator /]",
+                "[Number 2]",
+                "[NewlineAndIndent \n]",
+                "[Operator /]",
+            ],
+        );
+    }
+
+    #[test]
+    fn should_lex_heregexes_with_interpolation() {
+        let code = "///^ #{prefix} \\/// ///i";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[OpenRegex ///]",
+                "[RegexFragment ^ ]",
+                "[OpenInterpolation #{]",
+                "[InterpolatedCode prefix]",
+                "[CloseInterpolation }]",
+                "[RegexFragment  \\/// ]",
+                "[CloseRegex ///i]",
+            ],
+        );
+
+        let err = get_lexer("///abc").try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedRegex);
+    }
+
+    #[test]
+    fn mode_stack_should_track_nesting_without_recursion() {
+        let code = r#""a #{"b #{c"#;
+        let mut lexer = get_lexer(code);
+        let mut deepest = vec![];
+        while let Some(Ok(_)) = lexer.next() {
+            if lexer.modes.len() > deepest.len() {
+                deepest = lexer.modes.clone();
+            }
+        }
+        assert_eq!(
+            deepest,
+            vec![
+                LexMode::Code,
+                LexMode::DoubleString,
+                LexMode::Interpolation,
+                LexMode::DoubleString,
+                LexMode::Interpolation,
+            ]
+        );
+    }
+
+    #[test]
+    fn resuming_from_any_saved_line_state_should_reproduce_the_rest_of_the_tokens() {
+        let code = "40 + \"one\n  #{two\n}three\"\n\"\"\"\nfour\n\"\"\"\n### five\nsix ###\n///\n7 #{8}\n///\n9";
+        let mut lexer = get_lexer(code);
+        let mut all: Vec<Token> = vec![];
+        let mut saved: Vec<(usize, LexerState)> = vec![];
+        loop {
+            if let Some(state) = lexer.line_state() {
+                saved.push((all.len(), state));
+            }
+            match lexer.next() {
+                Some(token) => all.push(token.unwrap()),
+                None => break,
+            }
+        }
+
+        // One per newline, except the one inside the herecomment.
+        assert_eq!(saved.len(), code.matches('\n').count() - 1);
+        assert!(saved
+            .iter()
+            .any(|s| s.1.modes.last() == Some(&LexMode::Heredoc)));
+        assert!(saved
+            .iter()
+            .any(|s| s.1.modes.last() == Some(&LexMode::Interpolation)));
+        assert!(saved
+            .iter()
+            .any(|s| s.1.modes.last() == Some(&LexMode::Regex)));
+
+        for (already_lexed, state) in saved {
+            let rest = Lexer::resume(code, &state).lex();
+            assert_eq!(&rest[..], &all[already_lexed..]);
+        }
+    }
+
+    fn nested_interpolations(depth: usize) -> String {
+        let mut code = String::new();
+        for _ in 0..depth {
+            code.push_str("\"#{");
+        }
+        for _ in 0..depth {
+            code.push_str("}\"");
+        }
+        code
+    }
+
+    #[test]
+    fn hostile_interpolation_nesting_should_be_a_clean_error() {
+        let code = nested_interpolations(50_000);
+        let err = get_lexer(&code).try_lex().unwrap_err();
+        assert_eq!(
+            err.kind,
+            LexErrorKind::LimitExceeded(Limit::InterpolationDepth, 256)
+        );
+        assert_eq!(err.position.index, 256 * 3 + 1);
+        assert_eq!(
+            format!("{}", err),
+            "interpolation depth is over the limit of 256 at 1:769"
+        );
+    }
+
+    #[test]
+    fn very_deep_nesting_should_not_overflow_the_stack_when_limits_are_lifted() {
+        let code = nested_interpolations(50_000);
+        let tokens = Lexer::with_limits(&code, LexLimits::unlimited())
+            .try_lex()
+            .unwrap();
+        assert_eq!(tokens.len(), 4 * 50_000);
+    }
+
+    #[test]
+    fn should_track_indent_depth_and_stop_at_the_limit() {
+        let mut code = String::from("a");
+        for depth in 1..10 {
+            code.push('\n');
+            code.push_str(&" ".repeat(depth));
+            code.push('a');
+        }
+
+        let mut lexer = get_lexer(&code);
+        lexer.try_lex().unwrap();
+        assert_eq!(lexer.indent_depth(), 9);
+
+        let limits = LexLimits {
+            max_indent_depth: 4,
+            ..LexLimits::default()
+        };
+        let err = Lexer::with_limits(&code, limits).try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::LimitExceeded(Limit::IndentDepth, 4));
+        assert_eq!(err.position.line_no, 6);
+    }
+
+    #[test]
+    fn outdenting_blank_and_comment_lines_should_not_count_as_indentation() {
+        let code = "a\n  b\n    c\n\n        # deep comment\n  d";
+        let mut lexer = get_lexer(code);
+        lexer.try_lex().unwrap();
+        assert_eq!(lexer.indent_depth(), 1);
+    }
+
+    #[test]
+    fn should_stop_at_the_token_and_input_size_limits() {
+        let limits = LexLimits {
+            max_token_count: 3,
+            ..LexLimits::default()
+        };
+        assert_eq!(
+            Lexer::with_limits("1 2", limits).try_lex().unwrap().len(),
+            3
+        );
+        let err = Lexer::with_limits("1 2 3", limits).try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::LimitExceeded(Limit::TokenCount, 3));
+        assert_eq!(err.position.index, 3);
+
+        let limits = LexLimits {
+            max_input_size: 4,
+            ..LexLimits::default()
+        };
+        let err = Lexer::with_limits("40 + 2", limits).try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::LimitExceeded(Limit::InputSize, 4));
+        assert_eq!(err.position.index, 0);
+    }
+
+    #[test]
+    fn literate_mode_should_turn_prose_into_comments_and_lex_the_code_blocks() {
+        let code = "Squares things.\n    Still prose\n\n    a + 2\n      b\n\nMore \"prose\" ?\n    c\n\n\tq";
+        let mut lexer = Lexer::literate(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Comment Squares things.]",
+                "[NewlineAndIndent \n    ]",
+                // Indented, but right after prose, so still prose.
+                "[Comment Still prose]",
+                "[NewlineAndIndent \n]",
+                "[NewlineAndIndent \n    ]",
+                "[Word a]",
+                "[Whitespace  ]",
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[Number 2]",
+                "[NewlineAndIndent \n      ]",
+                "[Word b]",
+                "[NewlineAndIndent \n]",
+                "[NewlineAndIndent \n]",
+                "[Comment More \"prose\" ?]",
+                "[NewlineAndIndent \n    ]",
+                "[Comment c]",
+                "[NewlineAndIndent \n]",
+                "[NewlineAndIndent \n\t]",
+                "[Word q]",
+            ],
+        );
+        assert_eq!(lexer.indent_depth(), 0);
+    }
+
+    #[test]
+    fn literate_positions_should_point_into_the_litcoffee_file() {
+        let err = Lexer::literate("Intro\n\n    a ? b").try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnexpectedChar('?'));
+        assert_eq!((err.position.line_no, err.position.col_no), (3, 6));
+
+        let code = "    a\n      b\nprose";
+        let mut lexer = Lexer::literate(code);
+        let tokens = lexer.lex();
+        assert_eq!(tokens[3].span.start_pos.line_no, 2);
+        assert_eq!(tokens[3].span.start_pos.col_no, 6);
+        assert_eq!(get_region(&code, &tokens[5]), "prose");
+        // Code lines are measured from the edge of the code block, and prose doesn't close anything.
+        assert_eq!(lexer.indent_depth(), 1);
+    }
+
+    #[test]
+    fn replaying_literate_tokens_should_reproduce_the_lexer_state() {
+        let code = "Prose\n\n    a\n  \tb\n\n\t  c /d/\nprose / \"\n\n    e";
+        let mut lexer = Lexer::literate(code);
+        let mut state = LexerState::start_literate(code);
+        assert_eq!(lexer.state(), state);
+        while let Some(token) = lexer.next() {
+            state.advance(&token.unwrap(), code);
+            assert_eq!(lexer.state(), state);
+        }
+    }
+
+    #[test]
+    fn plain_coffee_should_not_care_about_code_blocks() {
+        let code = "Prose\n    a";
+        let tokens = Lexer::new(code).lex();
+        assert_eq!(tokens[0].tag, super::Word);
+    }
+
+    #[test]
+    fn mixed_indentation_should_leave_diagnostics_at_the_offending_lines() {
+        let code = "a\n    b\n\tc\n    d\n\n  # fine, comment lines don't count\ne\n\tf";
+        let mut lexer = get_lexer(code);
+        lexer.try_lex().unwrap();
+        assert_eq!(lexer.indent_style(), Some(IndentStyle::Spaces(4)));
+
+        let found: Vec<(IndentDiagnosticKind, usize, usize)> = lexer
+            .diagnostics()
+            .iter()
+            .map(|d| (d.kind, d.position.line_no, d.position.col_no))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (IndentDiagnosticKind::InconsistentWithBlock, 3, 0),
+                (IndentDiagnosticKind::InconsistentWithBlock, 4, 0),
+                (
+                    IndentDiagnosticKind::InconsistentWithFile(IndentStyle::Spaces(4)),
+                    8,
+                    0
+                ),
+            ]
+        );
+        assert_eq!(
+            lexer.diagnostics()[0].to_string(),
+            "indentation mixes tabs and spaces differently from the line before at 3:0"
+        );
+        assert_eq!(&code[lexer.diagnostics()[2].position.index..], "\tf");
+    }
+
+    #[test]
+    fn consistent_tabs_should_not_get_diagnostics() {
+        let mut lexer = get_lexer("a\n\tb\n\t\tc\n\td\ne");
+        lexer.try_lex().unwrap();
+        assert_eq!(lexer.indent_style(), Some(IndentStyle::Tabs));
+        assert!(lexer.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn crlf_and_cr_line_endings_should_lex_like_newlines() {
+        let code = "a\r\n  b \"c\r\nd\" # e\r  f\r\n";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Word a]",
+                "[NewlineAndIndent \r\n  ]",
+                "[Word b]",
+                "[Whitespace  ]",
+                "[OpenQuote \"]",
+                "[StringFragment c]",
+                "[StringFragment \r\nd]",
+                "[CloseQuote \"]",
+                "[Whitespace  ]",
+                "[Comment # e]",
+                "[NewlineAndIndent \r  ]",
+                "[Word f]",
+                "[NewlineAndIndent \r\n]",
+            ],
+        );
+        let f = tokens[11].span.start_pos;
+        assert_eq!((f.line_no, f.col_no), (4, 2));
+        assert_eq!(lexer.indent_depth(), 1);
+        assert!(lexer.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn a_bom_should_be_skipped_and_a_shebang_should_get_its_own_token() {
+        let code = "\u{feff}#!/usr/bin/env coffee\n#! just a comment\n40";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Shebang #!/usr/bin/env coffee]",
+                "[NewlineAndIndent \n]",
+                "[Comment #! just a comment]",
+                "[NewlineAndIndent \n]",
+                "[Number 40]",
+            ],
+        );
+        assert_eq!(tokens[0].span.start_pos.index, 3);
+        assert_eq!(tokens[0].span.start_pos.col_no, 0);
+        assert_eq!(tokens[4].span.start_pos.line_no, 3);
+        assert_eq!(get_lexer("\u{feff}").lex(), vec![]);
+    }
+
+    #[test]
+    fn a_literate_file_can_start_with_a_shebang() {
+        let code = "#!/usr/bin/env coffee\r\n\r\n    a\r\nprose";
+        let tags: Vec<_> = Lexer::literate(code).lex().iter().map(|t| t.tag).collect();
+        assert_eq!(
+            tags,
+            vec![
+                super::Shebang,
+                super::NewlineAndIndent,
+                super::NewlineAndIndent,
+                super::Word,
+                super::NewlineAndIndent,
+                super::Comment,
+            ]
+        );
+    }
+
+    #[test]
+    fn jsx_should_lex_tags_attributes_text_and_expressions() {
+        let code = "<div className={cls} id=\"main\">hi {name}</div>";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[JsxTagOpen <]",
+                "[JsxTagName div]",
+                "[Whitespace  ]",
+                "[JsxAttribute className]",
+                "[Operator =]",
+                "[OpenJsxExpression {]",
+                "[Word cls]",
+                "[CloseJsxExpression }]",
+                "[Whitespace  ]",
+                "[JsxAttribute id]",
+                "[Operator =]",
+                "[JsxAttributeValue \"main\"]",
+                "[JsxTagClose >]",
+                "[JsxText hi ]",
+                "[OpenJsxExpression {]",
+                "[Word name]",
+                "[CloseJsxExpression }]",
+                "[JsxTagOpen </]",
+                "[JsxTagName div]",
+                "[JsxTagClose >]",
+            ],
+        );
+        assert_eq!(lexer.modes, vec![LexMode::Code]);
+    }
+
+    #[test]
+    fn jsx_should_nest_elements_fragments_and_braces() {
+        let code = "x + <>\n  <a-b data-x='1'/>{ {a} }<i>{ {} }</i>\n</> + 1";
+        let mut lexer = get_lexer(code);
+        let tokens = lexer.lex();
+        assert_tokens_match(
+            &lexer,
+            &tokens,
+            vec![
+                "[Word x]",
+                "[Whitespace  ]",
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[JsxTagOpen <]",
+                "[JsxTagClose >]",
+                "[JsxText \n  ]",
+                "[JsxTagOpen <]",
+                "[JsxTagName a-b]",
+                "[Whitespace  ]",
+                "[JsxAttribute data-x]",
+                "[Operator =]",
+                "[JsxAttributeValue '1']",
+                "[JsxTagClose />]",
+                "[OpenJsxExpression {]",
+                "[Whitespace  ]",
+                "[Operator {]",
+                "[Word a]",
+                "[Operator }]",
+                "[Whitespace  ]",
+                "[CloseJsxExpression }]",
+                "[JsxTagOpen <]",
+                "[JsxTagName i]",
+                "[JsxTagClose >]",
+                "[OpenJsxExpression {]",
+                "[Whitespace  ]",
+                "[Operator {]",
+                "[Operator }]",
+                "[Whitespace  ]",
+                "[CloseJsxExpression }]",
+                "[JsxTagOpen </]",
+                "[JsxTagName i]",
+                "[JsxTagClose >]",
+                "[JsxText \n]",
+                "[JsxTagOpen </]",
+                "[JsxTagClose >]",
+                "[Whitespace  ]",
+                "[Operator +]",
+                "[Whitespace  ]",
+                "[Number 1]",
+            ],
+        );
+    }
+
+    #[test]
+    fn a_less_than_should_only_open_jsx_where_the_reference_lexer_would() {
+        let tags = |code: &str| -> Vec<String> {
+            let tokens = get_lexer(code).lex();
+            tokens
+                .iter()
+                .filter(|t| !t.tag.is_trivia())
+                .map(|t| t.text(&code))
+                .collect()
+        };
+        assert_eq!(tags("a<b"), vec!["[Word a]", "[Operator <]", "[Word b]"]);
+        assert_eq!(tags("a < b"), vec!["[Word a]", "[Operator <]", "[Word b]"]);
+        assert_eq!(
+            tags("1<=2"),
+            vec!["[Number 1]", "[Operator <=]", "[Number 2]"]
+        );
+        assert_eq!(
+            tags("a >>> 2"),
+            vec!["[Word a]", "[Operator >>>]", "[Number 2]"]
+        );
+        // Spaced before but not after: a <b is an element, as in CoffeeScript 2.
+        assert_eq!(
+            tags("a <b/>"),
+            vec![
+                "[Word a]",
+                "[JsxTagOpen <]",
+                "[JsxTagName b]",
+                "[JsxTagClose />]"
+            ]
+        );
+        // Nothing there to compare.
+        assert_eq!(
+            tags("+<b/>"),
+            vec![
+                "[Operator +]",
+                "[JsxTagOpen <]",
+                "[JsxTagName b]",
+                "[JsxTagClose />]"
+            ]
+        );
+        // An element has a value, so a / after one divides.
+        assert_eq!(tags("<b/> / 2")[3..], ["[Operator /]", "[Number 2]"]);
+
+        let cs1 = LexerOptions {
+            dialect: Dialect::CoffeeScript1,
+            ..LexerOptions::default()
+        };
+        let tokens = Lexer::with_options("a <b/>", &cs1).lex();
+        assert_eq!(tokens[2].text(&"a <b/>"), "[Operator <]");
+    }
+
+    #[test]
+    fn unterminated_jsx_should_be_an_error_and_text_should_break_at_newlines() {
+        let err = get_lexer("<a>\ntext").try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedJsx);
+        let err = get_lexer("<a b=\"c\n/>").try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedString);
+
+        let code = "<p>\r\n  one\r\n  <b\r\n    c=\"d\">two</b>\r\n</p>";
+        let tokens = get_lexer(code).lex();
+        let starts: Vec<usize> = tokens.iter().map(|t| t.span.start_pos.index).collect();
+        for (i, _) in code.match_indices("\r\n") {
+            assert!(starts.contains(&i), "no token starts at {}", i);
+        }
+        let mut state = LexerState::start(code);
+        for token in tokens.iter() {
+            state.advance(token, code);
+        }
+        assert_eq!(state.modes, vec![LexMode::Code]);
+    }
+
+    #[test]
+    fn replaying_jsx_tokens_should_reproduce_the_lexer_state() {
+        let code = "f + <A.B x={ {y} } z='1'>\n  {<c/>}<>t</>\n</A.B>\nd";
+        let mut lexer = get_lexer(code);
+        let mut state = LexerState::start(code);
+        let mut deepest = vec![];
+        while let Some(token) = lexer.next() {
+            state.advance(&token.unwrap(), code);
+            assert_eq!(lexer.state(), state);
+            if state.modes.len() > deepest.len() {
+                deepest = state.modes.clone();
+            }
+        }
+        assert_eq!(
+            deepest,
+            vec![
+                LexMode::Code,
+                LexMode::JsxTag,
+                LexMode::JsxExpression,
+                LexMode::Braces
+            ]
+        );
+    }
 }
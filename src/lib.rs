@@ -8,5 +8,22 @@
 pub use chomp::*;
 pub use lex::*;
 
+pub mod batch;
+pub mod buffer;
 pub mod chomp;
+pub mod cli;
+pub mod diff;
+pub mod highlight;
+pub mod incremental;
+pub mod indent;
 pub mod lex;
+pub mod line_index;
+pub mod options;
+pub mod pattern;
+pub mod reader_source;
+pub mod rules;
+pub mod semantic;
+pub mod source;
+pub mod stream;
+pub mod token_format;
+pub mod trivia;
@@ -0,0 +1,266 @@
+use chomp::{newline_len, Position};
+use std::borrow::Cow;
+use std::ops::Range;
+
+// Converts between byte offsets and the different ways people count columns. Position::col_no counts chars, which
+//   is nobody's favourite: the LSP wants UTF-16 code units and a terminal wants visual columns with tab stops. Build
+//   one of these per source (it's a single pass to find the line starts) and every consumer gets the column it needs
+//   out of the same Span.
+//
+// Lines are 1-based and columns 0-based, same as Position. Line breaks are \n, \r\n or a lone \r, same as the
+//   chomper, and a leading BOM doesn't count as a column.
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ColumnUnit {
+    // Bytes.
+    Utf8,
+    // Code units, what the LSP means by "character" unless told otherwise.
+    Utf16,
+    // What Position::col_no counts.
+    Char,
+    // Tabs jump to the next tab stop, everything else is one column wide.
+    Visual,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LineCol {
+    pub line_no: usize,
+    pub utf8_col: usize,
+    pub utf16_col: usize,
+    pub char_col: usize,
+    pub visual_col: usize,
+}
+
+impl LineCol {
+    pub fn col(&self, unit: ColumnUnit) -> usize {
+        match unit {
+            ColumnUnit::Utf8 => self.utf8_col,
+            ColumnUnit::Utf16 => self.utf16_col,
+            ColumnUnit::Char => self.char_col,
+            ColumnUnit::Visual => self.visual_col,
+        }
+    }
+}
+
+pub struct LineIndex<'code> {
+    code: &'code str,
+    // Byte offset where each line starts. Never empty. Borrowed when a SourceFile already worked them out.
+    line_starts: Cow<'code, [usize]>,
+    tab_width: usize,
+}
+
+impl<'code> LineIndex<'code> {
+    // Tab stops every 8 columns, like a terminal.
+    pub fn new(code: &'code str) -> LineIndex<'code> {
+        LineIndex::with_tab_width(code, 8)
+    }
+
+    pub fn with_tab_width(code: &'code str, tab_width: usize) -> LineIndex<'code> {
+        LineIndex {
+            code,
+            line_starts: Cow::Owned(line_starts(code)),
+            tab_width,
+        }
+    }
+
+    // line_starts has to be what the line_starts function gave for code.
+    pub fn from_line_starts(
+        code: &'code str,
+        line_starts: &'code [usize],
+        tab_width: usize,
+    ) -> LineIndex<'code> {
+        LineIndex {
+            code,
+            line_starts: Cow::Borrowed(line_starts),
+            tab_width,
+        }
+    }
+
+    pub fn code(&self) -> &'code str {
+        self.code
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    // The bytes of line line_no, not counting its line break. None past the last line.
+    pub fn line_range(&self, line_no: usize) -> Option<Range<usize>> {
+        if line_no == 0 || line_no > self.line_starts.len() {
+            return None;
+        }
+        let start = self.line_starts[line_no - 1];
+        let end = match self.line_starts.get(line_no) {
+            None => self.code.len(),
+            Some(&next) if self.code[..next].ends_with("\r\n") => next - 2,
+            Some(&next) => next - 1,
+        };
+        Some(start..end)
+    }
+
+    // Every kind of column for a byte offset. An offset inside a line break (or a BOM) counts as the end of its line
+    //   (or the start of the file).
+    pub fn line_col(&self, index: usize) -> LineCol {
+        let line_no = self
+            .line_starts
+            .partition_point(|&start| start <= index)
+            .max(1);
+        let line = self.line_range(line_no).unwrap();
+        let mut result = LineCol {
+            line_no,
+            utf8_col: 0,
+            utf16_col: 0,
+            char_col: 0,
+            visual_col: 0,
+        };
+        let end = index.clamp(line.start, line.end);
+        for c in self.code[line.start..end].chars() {
+            result = self.step(result, c);
+        }
+        result
+    }
+
+    pub fn line_col_of(&self, position: Position) -> LineCol {
+        self.line_col(position.index)
+    }
+
+    // The Position the chomper would have given for a byte offset.
+    pub fn position(&self, index: usize) -> Position {
+        let lc = self.line_col(index);
+        Position {
+            index: self.line_starts[lc.line_no - 1] + lc.utf8_col,
+            line_no: lc.line_no,
+            col_no: lc.char_col,
+        }
+    }
+
+    // The other way: the byte offset of a column on a line. A column that lands in the middle of a char (half of a
+    //   surrogate pair, part way through a tab) rounds down to the start of that char. The end of the line is a valid
+    //   column; anything past it is None.
+    pub fn offset(&self, line_no: usize, col: usize, unit: ColumnUnit) -> Option<usize> {
+        let line = self.line_range(line_no)?;
+        let mut lc = LineCol {
+            line_no,
+            utf8_col: 0,
+            utf16_col: 0,
+            char_col: 0,
+            visual_col: 0,
+        };
+        for c in self.code[line.clone()].chars() {
+            let next = self.step(lc, c);
+            if next.col(unit) > col {
+                return Some(line.start + lc.utf8_col);
+            }
+            lc = next;
+        }
+        if lc.col(unit) == col {
+            Some(line.end)
+        } else {
+            None
+        }
+    }
+
+    pub fn position_at(&self, line_no: usize, col: usize, unit: ColumnUnit) -> Option<Position> {
+        self.offset(line_no, col, unit)
+            .map(|index| self.position(index))
+    }
+
+    fn step(&self, lc: LineCol, c: char) -> LineCol {
+        LineCol {
+            line_no: lc.line_no,
+            utf8_col: lc.utf8_col + c.len_utf8(),
+            utf16_col: lc.utf16_col + c.len_utf16(),
+            char_col: lc.char_col + 1,
+            visual_col: if c == '\t' && self.tab_width > 0 {
+                (lc.visual_col / self.tab_width + 1) * self.tab_width
+            } else {
+                lc.visual_col + 1
+            },
+        }
+    }
+}
+
+// The byte offset of the start of every line in code.
+pub fn line_starts(code: &str) -> Vec<usize> {
+    let mut starts = vec![Position::start_of(code).index];
+    let mut index = starts[0];
+    while index < code.len() {
+        let newline = newline_len(&code[index..]);
+        if newline > 0 {
+            index += newline;
+            starts.push(index);
+        } else {
+            index = index + code[index..].chars().next().unwrap().len_utf8();
+        }
+    }
+    starts
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColumnUnit, LineCol, LineIndex};
+    use lex::Lexer;
+
+    #[test]
+    fn should_give_every_kind_of_column() {
+        let code = "ab\n\té😀x\r\ny";
+        let index = LineIndex::with_tab_width(code, 4);
+        assert_eq!(index.line_count(), 3);
+
+        let x = code.find('x').unwrap();
+        assert_eq!(
+            index.line_col(x),
+            LineCol {
+                line_no: 2,
+                utf8_col: 7,
+                utf16_col: 4,
+                char_col: 3,
+                visual_col: 6,
+            }
+        );
+        assert_eq!(index.line_col(code.find('y').unwrap()).line_no, 3);
+        assert_eq!(index.line_range(2), Some(3..x + 1));
+        assert_eq!(index.line_range(4), None);
+    }
+
+    #[test]
+    fn every_token_position_should_match_what_the_chomper_said() {
+        let code = "\u{feff}a\r\n\t\"é😀 #{b}\"\r  c # 😀\n///\n x\n///";
+        let index = LineIndex::new(code);
+        for token in Lexer::new(code).lex() {
+            for pos in [token.span.start_pos, token.span.end_pos].iter() {
+                assert_eq!(index.position(pos.index), *pos);
+                let lc = index.line_col_of(*pos);
+                assert_eq!(
+                    index.position_at(lc.line_no, lc.utf16_col, ColumnUnit::Utf16),
+                    Some(*pos)
+                );
+                assert_eq!(
+                    index.offset(lc.line_no, lc.visual_col, ColumnUnit::Visual),
+                    Some(pos.index)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn columns_inside_a_char_should_round_down_and_past_the_end_should_be_none() {
+        let code = "\t😀z";
+        let index = LineIndex::new(code);
+        assert_eq!(index.offset(1, 3, ColumnUnit::Visual), Some(0));
+        assert_eq!(index.offset(1, 8, ColumnUnit::Visual), Some(1));
+        assert_eq!(index.offset(1, 2, ColumnUnit::Utf16), Some(1));
+        assert_eq!(index.offset(1, 3, ColumnUnit::Utf16), Some(5));
+        assert_eq!(index.offset(1, 4, ColumnUnit::Utf16), Some(6));
+        assert_eq!(index.offset(1, 5, ColumnUnit::Utf16), None);
+        assert_eq!(index.offset(2, 0, ColumnUnit::Char), None);
+    }
+
+    #[test]
+    fn an_empty_source_should_still_have_a_line() {
+        let index = LineIndex::new("");
+        assert_eq!(index.line_count(), 1);
+        assert_eq!(index.line_col(0).line_no, 1);
+        assert_eq!(index.offset(1, 0, ColumnUnit::Utf8), Some(0));
+    }
+}
@@ -0,0 +1,288 @@
+use lex::LexLimits;
+
+// How to lex, for code bases that don't all agree. LexerOptions::default() is what Lexer::new does. Change what you
+//   need with struct update syntax:
+//
+//   let options = LexerOptions {
+//       dialect: Dialect::CoffeeScript1,
+//       strict_octal: true,
+//       ..LexerOptions::default()
+//   };
+//   let tokens = Lexer::with_options(code, &options).try_lex()?;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Dialect {
+    // 1.x, up to 1.12.
+    CoffeeScript1,
+    CoffeeScript2,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LexerOptions {
+    pub dialect: Dialect,
+    // Literate CoffeeScript (.litcoffee), see Lexer::literate.
+    pub literate: bool,
+    // yes, no, on and off mean true and false, as they always have. Some teams ban them; turn this off and they're
+    //   plain identifiers.
+    pub word_booleans: bool,
+    // Numbers with a leading zero (010, 09) are errors, the way the reference compiler has it. Off by default,
+    //   because plenty of old code has them.
+    pub strict_octal: bool,
+    // Tabs are fine in indentation. Off, a tab in indentation is an error.
+    pub allow_tabs: bool,
+    // Keep going after an error instead of stopping. The errors are kept (Lexer::errors), and a char the lexer can't
+    //   make anything of comes out as an Error token so the tokens still cover the whole source. Going over a limit
+    //   always stops.
+    pub recover: bool,
+    pub limits: LexLimits,
+}
+
+impl Default for LexerOptions {
+    fn default() -> LexerOptions {
+        LexerOptions {
+            dialect: Dialect::CoffeeScript2,
+            literate: false,
+            word_booleans: true,
+            strict_octal: false,
+            allow_tabs: true,
+            recover: false,
+            limits: LexLimits::default(),
+        }
+    }
+}
+
+// What a Word token is, going by its text.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WordKind {
+    Keyword,
+    Boolean,
+    // Can't be used as a name, but doesn't mean anything either.
+    Reserved,
+    Identifier,
+}
+
+const JS_KEYWORDS: &[&str] = &[
+    "true",
+    "false",
+    "null",
+    "this",
+    "new",
+    "delete",
+    "typeof",
+    "in",
+    "instanceof",
+    "return",
+    "throw",
+    "break",
+    "continue",
+    "debugger",
+    "yield",
+    "if",
+    "else",
+    "switch",
+    "for",
+    "while",
+    "do",
+    "try",
+    "catch",
+    "finally",
+    "class",
+    "extends",
+    "super",
+];
+
+const COFFEE_KEYWORDS: &[&str] = &[
+    "undefined",
+    "then",
+    "unless",
+    "until",
+    "loop",
+    "of",
+    "by",
+    "when",
+    "and",
+    "or",
+    "is",
+    "isnt",
+    "not",
+];
+
+// 2.x made these keywords.
+const COFFEE2_KEYWORDS: &[&str] = &["await", "import", "export", "default"];
+
+const COFFEE1_RESERVED: &[&str] = &[
+    "case",
+    "default",
+    "function",
+    "var",
+    "void",
+    "with",
+    "const",
+    "let",
+    "enum",
+    "export",
+    "import",
+    "native",
+    "implements",
+    "interface",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "static",
+];
+
+const COFFEE2_RESERVED: &[&str] = &[
+    "case",
+    "function",
+    "var",
+    "void",
+    "with",
+    "const",
+    "let",
+    "enum",
+    "native",
+    "implements",
+    "interface",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "static",
+];
+
+const WORD_BOOLEANS: &[&str] = &["yes", "no", "on", "off"];
+
+impl Dialect {
+    pub fn is_keyword(&self, word: &str) -> bool {
+        JS_KEYWORDS.contains(&word)
+            || COFFEE_KEYWORDS.contains(&word)
+            || (*self == Dialect::CoffeeScript2 && COFFEE2_KEYWORDS.contains(&word))
+    }
+
+    pub fn is_reserved(&self, word: &str) -> bool {
+        match *self {
+            Dialect::CoffeeScript1 => COFFEE1_RESERVED.contains(&word),
+            Dialect::CoffeeScript2 => COFFEE2_RESERVED.contains(&word),
+        }
+    }
+}
+
+impl LexerOptions {
+    pub fn is_boolean(&self, word: &str) -> bool {
+        word == "true" || word == "false" || (self.word_booleans && WORD_BOOLEANS.contains(&word))
+    }
+
+    pub fn word_kind(&self, word: &str) -> WordKind {
+        if self.is_boolean(word) {
+            WordKind::Boolean
+        } else if self.dialect.is_keyword(word) {
+            WordKind::Keyword
+        } else if self.dialect.is_reserved(word) {
+            WordKind::Reserved
+        } else {
+            WordKind::Identifier
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dialect, LexerOptions, WordKind};
+    use lex::TokenTag::*;
+    use lex::{LexErrorKind, Lexer};
+
+    #[test]
+    fn words_should_mean_what_the_dialect_says() {
+        let cs1 = LexerOptions {
+            dialect: Dialect::CoffeeScript1,
+            ..LexerOptions::default()
+        };
+        let cs2 = LexerOptions::default();
+        assert_eq!(cs1.word_kind("await"), WordKind::Identifier);
+        assert_eq!(cs2.word_kind("await"), WordKind::Keyword);
+        assert_eq!(cs1.word_kind("import"), WordKind::Reserved);
+        assert_eq!(cs2.word_kind("import"), WordKind::Keyword);
+        assert_eq!(cs2.word_kind("unless"), WordKind::Keyword);
+        assert_eq!(cs2.word_kind("true"), WordKind::Boolean);
+        assert_eq!(cs2.word_kind("yes"), WordKind::Boolean);
+        assert_eq!(cs2.word_kind("x"), WordKind::Identifier);
+
+        let no_yes = LexerOptions {
+            word_booleans: false,
+            ..LexerOptions::default()
+        };
+        assert_eq!(no_yes.word_kind("yes"), WordKind::Identifier);
+        assert_eq!(no_yes.word_kind("false"), WordKind::Boolean);
+    }
+
+    #[test]
+    fn strict_octal_should_reject_leading_zeros() {
+        let code = "a + 010";
+        assert!(Lexer::new(code).try_lex().is_ok());
+        let strict = LexerOptions {
+            strict_octal: true,
+            ..LexerOptions::default()
+        };
+        let err = Lexer::with_options(code, &strict).try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::LeadingZero);
+        assert_eq!(err.position.col_no, 4);
+        assert!(Lexer::with_options("0 + 10", &strict).try_lex().is_ok());
+    }
+
+    #[test]
+    fn tabs_should_be_an_error_only_when_not_allowed() {
+        let code = "if x\n\ty\n";
+        assert!(Lexer::new(code).try_lex().is_ok());
+        let no_tabs = LexerOptions {
+            allow_tabs: false,
+            ..LexerOptions::default()
+        };
+        let err = Lexer::with_options(code, &no_tabs).try_lex().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::TabIndentation);
+        assert_eq!(err.position.line_no, 2);
+        // A tab after the indentation is no business of ours.
+        assert!(Lexer::with_options("a\t+ 1", &no_tabs).try_lex().is_ok());
+    }
+
+    #[test]
+    fn recovery_should_keep_going_and_keep_the_errors() {
+        let code = "a ? b + 010\n\"open";
+        let options = LexerOptions {
+            recover: true,
+            strict_octal: true,
+            ..LexerOptions::default()
+        };
+        let mut lexer = Lexer::with_options(code, &options);
+        let tokens = lexer.try_lex().unwrap();
+        assert_eq!(tokens[2].tag, Error);
+        assert_eq!(tokens[2].text(&code), "[Error ?]");
+        assert_eq!(tokens.iter().filter(|t| t.tag == Word).count(), 2);
+        let kinds: Vec<LexErrorKind> = lexer.errors().iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LexErrorKind::UnexpectedChar('?'),
+                LexErrorKind::LeadingZero,
+                LexErrorKind::UnterminatedString,
+            ]
+        );
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|t| &code[t.span.range()])
+                .collect::<String>(),
+            code
+        );
+    }
+
+    #[test]
+    fn literate_should_be_an_option_too() {
+        let options = LexerOptions {
+            literate: true,
+            ..LexerOptions::default()
+        };
+        let tokens = Lexer::with_options("Prose.\n\n    a", &options).lex();
+        assert_eq!(tokens[0].tag, Comment);
+    }
+}
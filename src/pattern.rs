@@ -0,0 +1,392 @@
+use chomp::{ChompResult, Chomper, Span};
+
+// Token shapes written down instead of hand-coded as chomp closures. A Pattern is plain data made of references, so
+//   it can be a const and costs nothing to build:
+//
+//   const AT_WORD: Pattern<'static> = Seq(&[
+//       Literal("@"),
+//       one_or_more(&Class(|c| c.is_alphanumeric())),
+//   ]);
+//   let cr = chomper.chomp_pattern(&AT_WORD);
+//
+// Matching works like a PEG: repetition is greedy, Alt takes the first choice that matches, and nothing goes back to
+//   try a shorter repetition or a later choice once a match is made. A pattern that doesn't match leaves the chomper
+//   where it was (it rewinds to a Checkpoint), so patterns can be tried one after another.
+//
+// Line breaks look like '\n' to patterns, same as they do to Chomper::peek, whether they're \n, \r\n or \r.
+
+#[derive(Debug, Copy, Clone)]
+pub enum Pattern<'p> {
+    // One char that passes the test.
+    Class(fn(char) -> bool),
+    // Exactly this text. A \n in it matches any line break, same as Chomper::looking_at.
+    Literal(&'p str),
+    Seq(&'p [Pattern<'p>]),
+    // The first of these that matches.
+    Alt(&'p [Pattern<'p>]),
+    Optional(&'p Pattern<'p>),
+    // As many times as it'll match, up to max, and no fewer than min.
+    Repeat {
+        pattern: &'p Pattern<'p>,
+        min: usize,
+        max: Option<usize>,
+    },
+    // Everything up to (not including) stop, or up to eof. An escape char and the char after it are taken together,
+    //   so an escaped stop doesn't stop it and neither does the thing after an escaped escape ("\\" then the closer).
+    //   An escape right before a line break is just a char, so this never swallows a line break that stop is
+    //   looking for.
+    Until {
+        stop: &'p Pattern<'p>,
+        escape: Option<char>,
+    },
+}
+
+pub use self::Pattern::*;
+
+pub const NEWLINE: Pattern<'static> = Literal("\n");
+
+pub const fn one_or_more<'p>(pattern: &'p Pattern<'p>) -> Pattern<'p> {
+    Repeat {
+        pattern,
+        min: 1,
+        max: None,
+    }
+}
+
+pub const fn zero_or_more<'p>(pattern: &'p Pattern<'p>) -> Pattern<'p> {
+    Repeat {
+        pattern,
+        min: 0,
+        max: None,
+    }
+}
+
+impl<'p> Pattern<'p> {
+    // Adds the bytes a match could start with to bytes. false if there's no telling: a Class could start with
+    //   anything, and a pattern that can match nothing starts anywhere.
+    fn first_bytes(&self, bytes: &mut Vec<u8>) -> bool {
+        match *self {
+            Literal(text) => match text.as_bytes().first() {
+                None => false,
+                Some(&b'\n') => {
+                    bytes.extend_from_slice(b"\n\r");
+                    true
+                }
+                Some(&b) => {
+                    bytes.push(b);
+                    true
+                }
+            },
+            Seq(patterns) => patterns.first().is_some_and(|p| p.first_bytes(bytes)),
+            Alt(patterns) => !patterns.is_empty() && patterns.iter().all(|p| p.first_bytes(bytes)),
+            Repeat { pattern, min, .. } => min > 0 && pattern.first_bytes(bytes),
+            Class(_) | Optional(_) | Until { .. } => false,
+        }
+    }
+}
+
+impl<'ci> Chomper<'ci> {
+    // Chomps whatever pattern matches. None if it doesn't match, with nothing chomped. Patterns that can match
+    //   nothing at all (Optional, Until, a Repeat with min 0) give an empty span when they do, not None. hit_eof says
+    //   whether the match ran right up to the end of the code.
+    pub fn chomp_pattern(&mut self, pattern: &Pattern) -> Option<ChompResult> {
+        if self.is_eof {
+            return None;
+        }
+        let start = self.position();
+        if !self.match_pattern(pattern) {
+            return None;
+        }
+        Some(ChompResult {
+            span: Span::new(start, self.position(), self.file),
+            hit_eof: self.peek().is_none(),
+        })
+    }
+
+    // Whether pattern matches what's coming up, without chomping any of it.
+    pub fn looking_at_pattern(&mut self, pattern: &Pattern) -> bool {
+        let checkpoint = self.checkpoint();
+        let matched = self.match_pattern(pattern);
+        self.rewind(checkpoint);
+        matched
+    }
+
+    fn match_pattern(&mut self, pattern: &Pattern) -> bool {
+        let checkpoint = self.checkpoint();
+        let matched = match *pattern {
+            Class(test) => match self.peek() {
+                Some(c) if test(c) => {
+                    self.next();
+                    true
+                }
+                _ => false,
+            },
+            Literal(text) => {
+                if self.looking_at(text) {
+                    for _ in text.chars() {
+                        self.next();
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            Seq(patterns) => patterns.iter().all(|p| self.match_pattern(p)),
+            Alt(patterns) => patterns.iter().any(|p| self.match_pattern(p)),
+            Optional(pattern) => {
+                self.match_pattern(pattern);
+                true
+            }
+            Repeat { pattern, min, max } => {
+                let mut count = 0;
+                if let Class(test) = *pattern {
+                    count = self.skip_ascii_run(test, max);
+                }
+                while max.is_none_or(|max| count < max) {
+                    let before = self.index;
+                    if !self.match_pattern(pattern) {
+                        break;
+                    }
+                    count += 1;
+                    // Something that matched nothing will match nothing forever.
+                    if self.index == before {
+                        count = count.max(min);
+                        break;
+                    }
+                }
+                count >= min
+            }
+            Until { stop, escape } => {
+                // Where stop could start, or an escape. Everything in between can be skipped without looking.
+                let mut candidates = vec![];
+                let fast = stop.first_bytes(&mut candidates) && escape.is_none_or(|e| e.is_ascii());
+                if let Some(e) = escape {
+                    candidates.push(e as u8);
+                }
+                loop {
+                    if fast {
+                        let candidate = self.next_candidate(&candidates);
+                        self.skip_to(candidate);
+                    }
+                    let c = match self.peek() {
+                        Some(c) => c,
+                        None => break,
+                    };
+                    if self.looking_at_pattern(stop) {
+                        break;
+                    }
+                    self.next();
+                    if Some(c) == escape && self.peek().is_some_and(|e| e != '\n') {
+                        self.next();
+                    }
+                }
+                true
+            }
+        };
+        if !matched {
+            self.rewind(checkpoint);
+        }
+        matched
+    }
+
+    // Skips the ASCII chars coming up that pass test, up to max of them, all at once. Line breaks are left for the
+    //   slow path. Returns how many it skipped.
+    fn skip_ascii_run(&mut self, test: fn(char) -> bool, max: Option<usize>) -> usize {
+        let max = max.unwrap_or(usize::MAX);
+        let mut skipped = 0;
+        // A run can go on past what's on hand, so keep going until it stops short.
+        loop {
+            let text = self.text().as_bytes();
+            let wanted = text.len().min(max - skipped);
+            let run = text
+                .iter()
+                .take(wanted)
+                .take_while(|&&b| b.is_ascii() && b != b'\n' && b != b'\r' && test(b as char))
+                .count();
+            self.skip_to(self.index + run);
+            skipped += run;
+            if run == 0 || run < wanted {
+                return skipped;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        one_or_more, zero_or_more, Alt, Class, Literal, Optional, Pattern, Repeat, Seq, Until,
+        NEWLINE,
+    };
+    use chomp::fixtures::{chompers_everywhere, CORPUS};
+    use chomp::Chomper;
+
+    const DIGITS: Pattern<'static> = one_or_more(&Class(|c| c.is_ascii_digit()));
+
+    // An optionally signed number, with or without a fraction.
+    const NUMBER: Pattern<'static> = Seq(&[
+        Optional(&Alt(&[Literal("+"), Literal("-")])),
+        DIGITS,
+        Optional(&Seq(&[Literal("."), DIGITS])),
+    ]);
+
+    fn chomped(code: &str, pattern: &Pattern) -> Option<String> {
+        let mut chomper = Chomper::new(code);
+        chomper
+            .chomp_pattern(pattern)
+            .map(|cr| chomper.value(&cr).to_string())
+    }
+
+    #[test]
+    fn should_match_sequences_alternatives_and_options() {
+        assert_eq!(chomped("-42.5 + x", &NUMBER), Some("-42.5".to_string()));
+        assert_eq!(chomped("42.x", &NUMBER), Some("42".to_string()));
+        assert_eq!(chomped("+.5", &NUMBER), None);
+        assert_eq!(chomped("x", &Optional(&NUMBER)), Some("".to_string()));
+    }
+
+    #[test]
+    fn a_failed_match_should_leave_the_chomper_where_it_was() {
+        let mut chomper = Chomper::new("12.x");
+        let strict = Seq(&[DIGITS, Literal("."), DIGITS]);
+        let before = chomper.checkpoint();
+        assert!(chomper.chomp_pattern(&strict).is_none());
+        assert_eq!(chomper.checkpoint(), before);
+        assert!(chomper.looking_at_pattern(&DIGITS));
+        assert_eq!(chomper.checkpoint(), before);
+
+        let cr = chomper.chomp_pattern(&DIGITS).unwrap();
+        assert_eq!(chomper.value(&cr), "12");
+        assert!(!cr.hit_eof);
+        assert_eq!(chomper.index, 2);
+    }
+
+    #[test]
+    fn repeat_should_respect_min_and_max() {
+        let a = Class(|c| c == 'a');
+        let two_or_three = Repeat {
+            pattern: &a,
+            min: 2,
+            max: Some(3),
+        };
+        assert_eq!(chomped("a", &two_or_three), None);
+        assert_eq!(chomped("aab", &two_or_three), Some("aa".to_string()));
+        assert_eq!(chomped("aaaaa", &two_or_three), Some("aaa".to_string()));
+
+        // An inner pattern that matches nothing mustn't loop forever.
+        let maybe_a = Optional(&a);
+        let nothing = zero_or_more(&maybe_a);
+        assert_eq!(chomped("b", &nothing), Some("".to_string()));
+    }
+
+    #[test]
+    fn until_should_step_over_escapes() {
+        let string_body = Until {
+            stop: &Alt(&[Literal("\""), NEWLINE]),
+            escape: Some('\\'),
+        };
+        assert_eq!(
+            chomped(r#"a\"b" + 1"#, &string_body),
+            Some(r#"a\"b"#.to_string())
+        );
+        assert_eq!(
+            chomped(r#"a\\" + 1"#, &string_body),
+            Some(r#"a\\"#.to_string())
+        );
+        assert_eq!(chomped("a\\\r\nb", &string_body), Some("a\\".to_string()));
+
+        let mut chomper = Chomper::new("no closer");
+        let cr = chomper.chomp_pattern(&string_body).unwrap();
+        assert!(cr.hit_eof);
+        assert_eq!(chomper.value(&cr), "no closer");
+    }
+
+    #[test]
+    fn line_breaks_should_all_look_like_newlines() {
+        let line = Seq(&[Literal("a\nb"), NEWLINE]);
+        for code in ["a\nb\n", "a\r\nb\r\n", "a\rb\r"].iter() {
+            let mut chomper = Chomper::new(code);
+            let cr = chomper.chomp_pattern(&line).unwrap();
+            assert_eq!(cr.span.end_pos.line_no, 3);
+            assert_eq!(cr.span.end_pos.index, code.len());
+            assert!(cr.hit_eof);
+        }
+    }
+
+    // Patterns that say the same thing two ways: the first of each pair can take a fast path (Literal stops, a Repeat
+    //   of a Class), the second can't (Class stops, a Repeat of an Alt).
+    #[test]
+    fn fast_patterns_should_match_what_their_slow_spellings_do() {
+        let string_stops = [Literal("\""), Literal("#{"), NEWLINE];
+        let slow_string_stops = [
+            Class(|c| c == '"'),
+            Seq(&[Class(|c| c == '#'), Class(|c| c == '{')]),
+            Class(|c| c == '\n'),
+        ];
+        let heredoc_stops = [Literal("###"), Literal("é")];
+        let slow_heredoc_stops = [
+            Seq(&[
+                Class(|c| c == '#'),
+                Class(|c| c == '#'),
+                Class(|c| c == '#'),
+            ]),
+            Class(|c| c == 'é'),
+        ];
+        let space = Class(|c| c.is_whitespace() && c != '\n');
+        let spaces = [space];
+        let slow_space = Alt(&spaces);
+        let pairs = [
+            (
+                Until {
+                    stop: &Alt(&string_stops),
+                    escape: Some('\\'),
+                },
+                Until {
+                    stop: &Alt(&slow_string_stops),
+                    escape: Some('\\'),
+                },
+            ),
+            (
+                Until {
+                    stop: &Alt(&heredoc_stops),
+                    escape: None,
+                },
+                Until {
+                    stop: &Alt(&slow_heredoc_stops),
+                    escape: None,
+                },
+            ),
+            (zero_or_more(&space), zero_or_more(&slow_space)),
+            (
+                Repeat {
+                    pattern: &space,
+                    min: 2,
+                    max: Some(3),
+                },
+                Repeat {
+                    pattern: &slow_space,
+                    min: 2,
+                    max: Some(3),
+                },
+            ),
+        ];
+        for code in CORPUS.iter() {
+            for chomper in chompers_everywhere(code) {
+                let start = chomper.position();
+                for (quick, slow) in pairs.iter() {
+                    let mut fast = Chomper::starting_at(code, start);
+                    let mut slow_chomper = Chomper::starting_at(code, start);
+                    assert_eq!(
+                        fast.chomp_pattern(quick),
+                        slow_chomper.chomp_pattern(slow),
+                        "{:?} from {} with {:?}",
+                        code,
+                        start.index,
+                        quick
+                    );
+                    assert_eq!(fast.position(), slow_chomper.position());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use chomp::CharSource;
+use std::io::{self, Read};
+use std::str;
+
+// A CharSource for input that isn't all in memory, like a generated bundle piped through the build. It reads from
+//   any io::Read a chunk at a time into a buffer that slides along behind the chomper, so lexing a big file doesn't
+//   mean holding all of it. Hand one to Lexer::with_source (or use Lexer::from_reader) and the tokens come out the
+//   same as lexing the whole text: same byte indexes, same line and col numbers, same \r\n, \r and leading BOM.
+//
+// Input that isn't UTF-8, or a reader that fails, ends the input there. The Lexer turns that into an Unreadable
+//   error instead of carrying on as if the file had ended.
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct ReaderSource<'r> {
+    reader: Box<dyn Read + 'r>,
+    // Text read so far and still needed. buffer[0] is byte offset `offset` of the whole input.
+    buffer: String,
+    offset: usize,
+    // The start of a UTF-8 sequence that was cut off at the end of a chunk.
+    partial: Vec<u8>,
+    reader_done: bool,
+    error: Option<io::Error>,
+    // Nothing before this is needed any more. It's dropped from the buffer the next time it has to make room.
+    keep: usize,
+    chunk_size: usize,
+}
+
+impl<'r> ReaderSource<'r> {
+    pub fn new<R: Read + 'r>(reader: R) -> ReaderSource<'r> {
+        ReaderSource::with_chunk_size(reader, DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size<R: Read + 'r>(reader: R, chunk_size: usize) -> ReaderSource<'r> {
+        assert!(
+            chunk_size > 0,
+            "A ReaderSource can't read 0 bytes at a time."
+        );
+        ReaderSource {
+            reader: Box::new(reader),
+            buffer: String::new(),
+            offset: 0,
+            partial: vec![],
+            reader_done: false,
+            error: None,
+            keep: 0,
+            chunk_size,
+        }
+    }
+
+    fn read_chunk(&mut self) {
+        let mut chunk = vec![0; self.chunk_size];
+        let read = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(read) => break read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return self.fail(e),
+            }
+        };
+        if read == 0 {
+            self.reader_done = true;
+            if !self.partial.is_empty() {
+                self.fail(not_utf8());
+            }
+            return;
+        }
+
+        self.partial.extend_from_slice(&chunk[..read]);
+        let (valid, bad) = match str::from_utf8(&self.partial) {
+            Ok(text) => (text.len(), false),
+            // Cut off part way through a char. The rest of it is in the next chunk.
+            Err(e) if e.error_len().is_none() => (e.valid_up_to(), false),
+            Err(e) => (e.valid_up_to(), true),
+        };
+        self.buffer
+            .push_str(str::from_utf8(&self.partial[..valid]).unwrap());
+        self.partial.drain(..valid);
+        if bad {
+            self.fail(not_utf8());
+        }
+    }
+
+    fn fail(&mut self, error: io::Error) {
+        self.reader_done = true;
+        self.error = Some(error);
+    }
+}
+
+impl<'r> CharSource for ReaderSource<'r> {
+    fn text_from(&self, index: usize) -> &str {
+        &self.buffer[index - self.offset..]
+    }
+
+    fn text_before(&self, index: usize) -> &str {
+        &self.buffer[..index - self.offset]
+    }
+
+    fn fill(&mut self, index: usize, bytes: usize) {
+        while self.buffer.len() - (index - self.offset) < bytes && !self.reader_done {
+            if self.keep > self.offset {
+                self.buffer.drain(..self.keep - self.offset);
+                self.offset = self.keep;
+            }
+            self.read_chunk();
+        }
+    }
+
+    fn forget_before(&mut self, index: usize) {
+        self.keep = self.keep.max(index);
+    }
+
+    fn len_read(&self) -> usize {
+        self.offset + self.buffer.len()
+    }
+
+    fn read_error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    fn whole(&self) -> Option<&str> {
+        None
+    }
+}
+
+fn not_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReaderSource;
+    use chomp::Source;
+    use lex::{LexErrorKind, Lexer};
+    use options::LexerOptions;
+    use std::io::{self, Read};
+
+    // Hands out at most a few bytes per read, so chars and \r\n get cut in half at chunk boundaries.
+    struct Trickle<'t> {
+        bytes: &'t [u8],
+        per_read: usize,
+    }
+
+    impl<'t> Read for Trickle<'t> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.per_read.min(buf.len()).min(self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn should_give_the_same_tokens_as_lexing_the_whole_text() {
+        let code =
+            "\u{feff}a \"é😀 #{b}\"\r\n\tc ###x\r###\n  d + /é\\/+/g\r\n  \"\"\"\n😀\"\"\" # é\n";
+        let literate = "\u{feff}Some prose é\r\n\n    a /b/ + \"😀\"\n\nmore\r\n";
+        for &(code, is_literate) in [(code, false), (literate, true)].iter() {
+            let options = LexerOptions {
+                literate: is_literate,
+                ..LexerOptions::default()
+            };
+            let whole = Lexer::with_options(code, &options).try_lex();
+            assert!(whole.as_ref().is_ok_and(|tokens| tokens.len() > 10));
+            for per_read in 1..6 {
+                for chunk_size in 1..6 {
+                    let reader = Trickle {
+                        bytes: code.as_bytes(),
+                        per_read,
+                    };
+                    let source = ReaderSource::with_chunk_size(reader, chunk_size);
+                    assert_eq!(Lexer::with_source(source, &options).try_lex(), whole);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_buffer_should_not_grow_with_the_input() {
+        let code = "some text on a line\n".repeat(10000);
+        let source = ReaderSource::with_chunk_size(code.as_bytes(), 256);
+        let mut lexer = Lexer::with_source(source, &LexerOptions::default());
+        assert_eq!(lexer.lex(), Lexer::new(&code).lex());
+        match *lexer.chomper().source() {
+            Source::Reader(ref source) => {
+                assert_eq!(source.offset + source.buffer.len(), code.len());
+                assert!(source.buffer.capacity() < 4 * 256);
+            }
+            Source::Str(_) => panic!("The lexer should be reading a stream."),
+        }
+    }
+
+    #[test]
+    fn bad_utf8_should_be_an_error() {
+        let bytes: &[u8] = b"ok \xff\xfe";
+        let source = ReaderSource::with_chunk_size(bytes, 2);
+        let err = Lexer::with_source(source, &LexerOptions::default())
+            .try_lex()
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            LexErrorKind::Unreadable(io::ErrorKind::InvalidData)
+        );
+        assert_eq!(err.position.index, 3);
+
+        // Cut off part way through the last char.
+        let truncated = &"é".as_bytes()[..1];
+        let err = Lexer::from_reader(truncated, &LexerOptions::default())
+            .try_lex()
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            LexErrorKind::Unreadable(io::ErrorKind::InvalidData)
+        );
+    }
+}
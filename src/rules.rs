@@ -0,0 +1,255 @@
+use lex::TokenTag::*;
+use lex::{Lexer, Token, TokenTag};
+use std::sync::{Arc, OnceLock};
+
+// What the lexer tries, in order, when it's sitting in plain code (not inside a string or a regex). Each rule ties
+//   a tag to a cheap test on the first char and the scan fn that does the real work. The first rule whose first_char
+//   test passes gets to scan; if its scan returns None without chomping anything, the next one gets a go.
+//
+// Library users can add their own rules for house-style tokens. A scan fn gets the whole Lexer, and does its
+//   chomping through Lexer::chomper. Rules run lowest priority number first; the built-in ones are spaced out by
+//   100 so there's room in between:
+//
+//   let mut rules = LexRules::builtin();
+//   rules.add(LexRule::new("at-sign", TokenTag::Custom(0), 50, |c| c == '@', |lexer| {
+//       let cr = lexer.chomper().chomp_count(1);
+//       Some(TokenTag::Custom(0).assert_at(cr))
+//   }));
+//   lexer.set_rules(&rules);
+
+pub type ScanFn = for<'a, 'li> fn(&'a mut Lexer<'li>) -> Option<Token>;
+
+#[derive(Copy, Clone)]
+pub struct LexRule {
+    pub name: &'static str,
+    // The tag the rule is there for. Some scan fns can come back with others too (a / is usually division, but
+    //   it's the slash rule that decides).
+    pub tag: TokenTag,
+    pub priority: u32,
+    pub first_char: fn(char) -> bool,
+    pub scan: ScanFn,
+}
+
+impl LexRule {
+    pub fn new(
+        name: &'static str,
+        tag: TokenTag,
+        priority: u32,
+        first_char: fn(char) -> bool,
+        scan: ScanFn,
+    ) -> LexRule {
+        assert_fits(tag);
+        LexRule {
+            name,
+            tag,
+            priority,
+            first_char,
+            scan,
+        }
+    }
+}
+
+// A set of rules, kept sorted by priority. Cheap to clone and to hand to any number of lexers.
+#[derive(Clone)]
+pub struct LexRules {
+    rules: Arc<[LexRule]>,
+}
+
+impl LexRules {
+    pub fn builtin() -> LexRules {
+        static BUILTIN: OnceLock<LexRules> = OnceLock::new();
+        BUILTIN
+            .get_or_init(|| LexRules {
+                rules: builtin_rules().into(),
+            })
+            .clone()
+    }
+
+    pub fn empty() -> LexRules {
+        LexRules {
+            rules: Vec::new().into(),
+        }
+    }
+
+    // Goes after any rules that already have the same priority.
+    pub fn add(&mut self, rule: LexRule) {
+        assert_fits(rule.tag);
+        let mut rules = self.rules.to_vec();
+        let at = rules.partition_point(|r| r.priority <= rule.priority);
+        rules.insert(at, rule);
+        self.rules = rules.into();
+    }
+
+    // Takes out every rule called name. Returns whether there were any.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.rules.len();
+        let rules: Vec<LexRule> = self
+            .rules
+            .iter()
+            .filter(|r| r.name != name)
+            .cloned()
+            .collect();
+        self.rules = rules.into();
+        self.rules.len() != before
+    }
+
+    pub fn rules(&self) -> &[LexRule] {
+        &self.rules
+    }
+}
+
+// Past TokenTag::MAX_CUSTOM_ID, a Custom tag has no byte of its own to go in a TokenBuffer as.
+fn assert_fits(tag: TokenTag) {
+    if let Custom(id) = tag {
+        assert!(
+            id <= TokenTag::MAX_CUSTOM_ID,
+            "Custom token ids only go up to {}, but got {}.",
+            TokenTag::MAX_CUSTOM_ID,
+            id
+        );
+    }
+}
+
+fn builtin_rules() -> Vec<LexRule> {
+    vec![
+        LexRule::new("word", Word, 100, Lexer::is_valid_first_char_of_word, |l| {
+            l.get_word()
+        }),
+        LexRule::new(
+            "newline",
+            NewlineAndIndent,
+            200,
+            |c| c == '\n',
+            |l| l.process_newline(),
+        ),
+        LexRule::new(
+            "double quote",
+            OpenQuote,
+            300,
+            |c| c == '\"',
+            |l| l.process_double_quote(),
+        ),
+        LexRule::new("slash", Regex, 400, |c| c == '/', |l| l.process_slash()),
+        LexRule::new(
+            "whitespace",
+            Whitespace,
+            500,
+            |c| c.is_whitespace(),
+            |l| l.get_whitespace(),
+        ),
+        LexRule::new(
+            "number",
+            Number,
+            600,
+            |c| c.is_ascii_digit(),
+            |l| l.get_number(),
+        ),
+        LexRule::new(
+            "operator",
+            Operator,
+            700,
+            |c| c == '+' || c == '-',
+            |l| l.get_operator(),
+        ),
+        LexRule::new(
+            "angle bracket",
+            Operator,
+            750,
+            |c| c == '<' || c == '>',
+            |l| l.process_angle_bracket(),
+        ),
+        LexRule::new("comment", Comment, 800, |c| c == '#', |l| l.get_comment()),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LexRule, LexRules};
+    use lex::TokenTag::*;
+    use lex::{LexErrorKind, Lexer, Token, TokenTag};
+    use pattern::{zero_or_more, Class, Literal, Pattern, Seq};
+
+    const AT: TokenTag = TokenTag::Custom(0);
+
+    const AT_WORD: Pattern<'static> =
+        Seq(&[Literal("@"), zero_or_more(&Class(|c| c.is_alphanumeric()))]);
+
+    fn scan_at_word(lexer: &mut Lexer) -> Option<Token> {
+        Some(AT.assert_at(lexer.chomper().chomp_pattern(&AT_WORD)))
+    }
+
+    #[test]
+    fn builtin_rules_should_be_in_priority_order() {
+        let rules = LexRules::builtin();
+        let priorities: Vec<u32> = rules.rules().iter().map(|r| r.priority).collect();
+        let mut sorted = priorities.clone();
+        sorted.sort();
+        assert_eq!(priorities, sorted);
+        assert_eq!(rules.rules()[0].tag, Word);
+    }
+
+    #[test]
+    fn users_should_be_able_to_add_their_own_tokens() {
+        let code = "@home + 1";
+        assert_eq!(
+            Lexer::new(code).try_lex().unwrap_err().kind,
+            LexErrorKind::UnexpectedChar('@')
+        );
+
+        let mut rules = LexRules::builtin();
+        rules.add(LexRule::new("at-word", AT, 50, |c| c == '@', scan_at_word));
+        let mut lexer = Lexer::new(code);
+        lexer.set_rules(&rules);
+        let tokens = lexer.lex();
+        assert_eq!(tokens[0].text(&code), "[Custom(0) @home]");
+        assert_eq!(tokens[2].tag, Operator);
+        assert!(!AT.is_trivia());
+    }
+
+    #[test]
+    fn a_rule_that_passes_should_let_the_next_one_try() {
+        fn never(_: &mut Lexer) -> Option<Token> {
+            None
+        }
+        let mut rules = LexRules::builtin();
+        rules.add(LexRule::new("greedy", AT, 0, |_| true, never));
+        let mut lexer = Lexer::new("40 + 2");
+        lexer.set_rules(&rules);
+        assert_eq!(lexer.lex().len(), 5);
+    }
+
+    #[test]
+    fn removing_a_rule_should_make_its_chars_unexpected() {
+        let mut rules = LexRules::builtin();
+        assert!(rules.remove("comment"));
+        assert!(!rules.remove("comment"));
+        let mut lexer = Lexer::new("1 # no comments here");
+        lexer.set_rules(&rules);
+        assert_eq!(
+            lexer.try_lex().unwrap_err().kind,
+            LexErrorKind::UnexpectedChar('#')
+        );
+        assert!(LexRules::empty().rules().is_empty());
+    }
+
+    #[test]
+    fn the_biggest_custom_id_should_still_fit_in_a_byte() {
+        let last = Custom(TokenTag::MAX_CUSTOM_ID);
+        let mut rules = LexRules::empty();
+        rules.add(LexRule::new("last", last, 0, |_| true, scan_at_word));
+        assert_eq!(last.to_u8(), u8::MAX);
+        assert_eq!(TokenTag::from_u8(u8::MAX), Some(last));
+    }
+
+    #[test]
+    #[should_panic(expected = "Custom token ids only go up to 223, but got 224.")]
+    fn a_custom_id_past_the_biggest_should_be_refused() {
+        LexRule::new("too far", Custom(224), 0, |_| true, scan_at_word);
+    }
+
+    #[test]
+    #[should_panic(expected = "Custom token ids only go up to 223, but got 230.")]
+    fn a_custom_tag_that_does_not_fit_should_not_become_a_builtin_one() {
+        Custom(230).to_u8();
+    }
+}
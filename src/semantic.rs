@@ -0,0 +1,484 @@
+use chomp::newline_len;
+use lex::TokenTag::*;
+use lex::{Lexer, Token, TokenTag};
+use line_index::LineIndex;
+use options::{LexerOptions, WordKind};
+use std::ops::Range;
+
+// What a token means to someone reading the code, for highlighting it. Most of it comes straight from the tag.
+//   Words need more: the options decide which ones are keywords, and what's around an identifier decides whether
+//   it's a property (a.b, @b, b: 1), a function (f = ->, f(x)) or a parameter ((a, b) ->). The lexer doesn't make
+//   tokens of . : ( ) or -> yet, so that part looks at the source text next to the word. Lex with
+//   LexerOptions::recover on to highlight code that has those in it.
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
+pub enum SemanticKind {
+    // Keywords, and the words that are reserved or booleans too.
+    Keyword,
+    Variable,
+    Property,
+    Function,
+    Parameter,
+    Number,
+    String,
+    // A backslash and the char it escapes, inside a string.
+    StringEscape,
+    Regex,
+    // The #{ } of an interpolation, and the { } around a JSX expression.
+    InterpolationPunctuation,
+    Comment,
+    Operator,
+    // A JSX tag name, with its < > or </ />.
+    Tag,
+    Attribute,
+}
+
+impl SemanticKind {
+    // The order of the LSP legend; a kind's index here is its token type number. This is a public API: kinds only
+    //   ever get added to the end.
+    pub const ALL: [SemanticKind; 14] = [
+        SemanticKind::Keyword,
+        SemanticKind::Variable,
+        SemanticKind::Property,
+        SemanticKind::Function,
+        SemanticKind::Parameter,
+        SemanticKind::Number,
+        SemanticKind::String,
+        SemanticKind::StringEscape,
+        SemanticKind::Regex,
+        SemanticKind::InterpolationPunctuation,
+        SemanticKind::Comment,
+        SemanticKind::Operator,
+        SemanticKind::Tag,
+        SemanticKind::Attribute,
+    ];
+
+    // The LSP's name for it, where it has one.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SemanticKind::Keyword => "keyword",
+            SemanticKind::Variable => "variable",
+            SemanticKind::Property => "property",
+            SemanticKind::Function => "function",
+            SemanticKind::Parameter => "parameter",
+            SemanticKind::Number => "number",
+            SemanticKind::String => "string",
+            SemanticKind::StringEscape => "stringEscape",
+            SemanticKind::Regex => "regexp",
+            SemanticKind::InterpolationPunctuation => "interpolation",
+            SemanticKind::Comment => "comment",
+            SemanticKind::Operator => "operator",
+            SemanticKind::Tag => "tag",
+            SemanticKind::Attribute => "attribute",
+        }
+    }
+
+    pub fn index(&self) -> u32 {
+        SemanticKind::ALL.iter().position(|k| k == self).unwrap() as u32
+    }
+
+    // What every token with tag is, going by the tag alone. Every Word comes out as a Variable here; classify is
+    //   what tells them apart. None for trivia that isn't a comment, JSX text, errors and custom tags.
+    pub fn of_tag(tag: TokenTag) -> Option<SemanticKind> {
+        match tag {
+            Number => Some(SemanticKind::Number),
+            Word => Some(SemanticKind::Variable),
+            Operator => Some(SemanticKind::Operator),
+            Herecomment | Comment | Shebang => Some(SemanticKind::Comment),
+            OpenQuote | StringFragment | CloseQuote | JsxAttributeValue => {
+                Some(SemanticKind::String)
+            }
+            OpenInterpolation | CloseInterpolation | OpenJsxExpression | CloseJsxExpression => {
+                Some(SemanticKind::InterpolationPunctuation)
+            }
+            // The code gets lexed on its own to find out what's in it.
+            InterpolatedCode => None,
+            TokenTag::Regex | OpenRegex | RegexFragment | CloseRegex => Some(SemanticKind::Regex),
+            JsxTagOpen | JsxTagName | JsxTagClose => Some(SemanticKind::Tag),
+            JsxAttribute => Some(SemanticKind::Attribute),
+            Whitespace | NewlineAndIndent | JsxText | TokenTag::Error | Custom(_) => None,
+        }
+    }
+
+    pub fn of_word_kind(kind: WordKind) -> SemanticKind {
+        match kind {
+            WordKind::Keyword | WordKind::Boolean | WordKind::Reserved => SemanticKind::Keyword,
+            WordKind::Identifier => SemanticKind::Variable,
+        }
+    }
+}
+
+// A byte range of the source and what it is. Ranges don't overlap and come in source order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SemanticToken {
+    pub range: Range<usize>,
+    pub kind: SemanticKind,
+}
+
+// The semantic tokens for tokens, which the lexer made out of code with options. Tokens without a kind are left
+//   out, string fragments are split around their escapes and the code in an interpolation gets classified too.
+pub fn classify(tokens: &[Token], code: &str, options: &LexerOptions) -> Vec<SemanticToken> {
+    let mut result = vec![];
+    classify_into(&mut result, tokens, code, 0, options);
+    result
+}
+
+// tokens were lexed out of code[offset..].
+fn classify_into(
+    result: &mut Vec<SemanticToken>,
+    tokens: &[Token],
+    code: &str,
+    offset: usize,
+    options: &LexerOptions,
+) {
+    for token in tokens {
+        let range = token.span.start_pos.index + offset..token.span.end_pos.index + offset;
+        match token.tag {
+            Word => {
+                let kind = classify_word(code, range.clone(), options);
+                result.push(SemanticToken { range, kind });
+            }
+            StringFragment => split_escapes(result, code, range),
+            InterpolatedCode => {
+                let inner_options = LexerOptions {
+                    literate: false,
+                    recover: true,
+                    ..*options
+                };
+                if let Ok(inner) =
+                    Lexer::with_options(&code[range.clone()], &inner_options).try_lex()
+                {
+                    classify_into(result, &inner, code, range.start, options);
+                }
+            }
+            tag => {
+                if let Some(kind) = SemanticKind::of_tag(tag) {
+                    result.push(SemanticToken { range, kind });
+                }
+            }
+        }
+    }
+}
+
+fn classify_word(code: &str, range: Range<usize>, options: &LexerOptions) -> SemanticKind {
+    let kind = SemanticKind::of_word_kind(options.word_kind(&code[range.clone()]));
+    if kind == SemanticKind::Keyword {
+        return kind;
+    }
+    let before = &code[..range.start];
+    let after = &code[range.end..];
+    if defines_function(after) || after.starts_with('(') {
+        SemanticKind::Function
+    } else if is_property_access(before) || is_object_key(after) {
+        SemanticKind::Property
+    } else if in_parameter_list(after) {
+        SemanticKind::Parameter
+    } else {
+        SemanticKind::Variable
+    }
+}
+
+fn skip_spaces(text: &str) -> &str {
+    text.trim_start_matches([' ', '\t'])
+}
+
+// What comes after a word that's followed by a colon, but not a ::.
+fn after_colon(text: &str) -> Option<&str> {
+    let text = skip_spaces(text);
+    if text.starts_with(':') && !text.starts_with("::") {
+        Some(&text[1..])
+    } else {
+        None
+    }
+}
+
+// a.b or @b, but not a range like a..b.
+fn is_property_access(before: &str) -> bool {
+    (before.ends_with('.') && !before.ends_with("..")) || before.ends_with('@')
+}
+
+fn is_object_key(after: &str) -> bool {
+    after_colon(after).is_some()
+}
+
+// f = -> or f: (a) => and the like.
+fn defines_function(after: &str) -> bool {
+    let value = match after_colon(after) {
+        Some(value) => value,
+        None => {
+            let text = skip_spaces(after);
+            if !text.starts_with('=') || text.starts_with("==") || text.starts_with("=>") {
+                return false;
+            }
+            &text[1..]
+        }
+    };
+    let mut value = skip_spaces(value);
+    if value.starts_with('(') {
+        value = match past_closing_paren(&value[1..]) {
+            Some(rest) => skip_spaces(rest),
+            None => return false,
+        };
+    }
+    value.starts_with("->") || value.starts_with("=>")
+}
+
+// Whether the word before after sits inside the parentheses of a function's parameters.
+fn in_parameter_list(after: &str) -> bool {
+    match past_closing_paren(after) {
+        Some(rest) => {
+            let rest = skip_spaces(rest);
+            rest.starts_with("->") || rest.starts_with("=>")
+        }
+        None => false,
+    }
+}
+
+// What's after the ) that closes a ( just before text, if it's on the same line.
+fn past_closing_paren(text: &str) -> Option<&str> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(&text[i + 1..]),
+            ')' => depth -= 1,
+            '\n' | '\r' => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+// A string fragment as String pieces, with a StringEscape for each backslash and the char after it. A backslash at
+//   the end of a line continues the string, and the line break isn't part of the escape.
+fn split_escapes(result: &mut Vec<SemanticToken>, code: &str, range: Range<usize>) {
+    let mut push = |range: Range<usize>, kind: SemanticKind| {
+        if !range.is_empty() {
+            result.push(SemanticToken { range, kind });
+        }
+    };
+    let mut start = range.start;
+    let mut chars = code[range.clone()].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+        let escape_start = range.start + i;
+        let escape_end = match chars.next() {
+            Some((_, '\n')) | Some((_, '\r')) | None => escape_start + 1,
+            Some((j, escaped)) => range.start + j + escaped.len_utf8(),
+        };
+        push(start..escape_start, SemanticKind::String);
+        push(escape_start..escape_end, SemanticKind::StringEscape);
+        start = escape_end;
+    }
+    push(start..range.end, SemanticKind::String);
+}
+
+// The LSP's semantic tokens encoding: five numbers per token (line, start column, length, type, modifiers), with
+//   line and start relative to the token before. Columns and lengths are UTF-16 code units, lines are 0-based, and
+//   the type is SemanticKind::index. A token that spans lines is sent as one per line, since not every client
+//   takes multiline tokens. No modifiers yet, so those are always 0.
+pub fn encode_lsp(tokens: &[SemanticToken], index: &LineIndex) -> Vec<u32> {
+    let code = index.code();
+    let mut data = vec![];
+    let mut last_line = 0;
+    let mut last_col = 0;
+    for token in tokens {
+        for piece in line_pieces(code, token.range.clone()) {
+            let at = index.line_col(piece.start);
+            let line = at.line_no - 1;
+            let col = at.utf16_col;
+            let length: usize = code[piece].chars().map(char::len_utf16).sum();
+            let delta_col = if line == last_line {
+                col - last_col
+            } else {
+                col
+            };
+            data.push((line - last_line) as u32);
+            data.push(delta_col as u32);
+            data.push(length as u32);
+            data.push(token.kind.index());
+            data.push(0);
+            last_line = line;
+            last_col = col;
+        }
+    }
+    data
+}
+
+// range, cut at its line breaks, without the line breaks or any empty pieces.
+fn line_pieces(code: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut pieces = vec![];
+    let mut start = range.start;
+    let mut i = range.start;
+    while i < range.end {
+        let byte = code.as_bytes()[i];
+        if byte == b'\n' || byte == b'\r' {
+            let newline = newline_len(&code[i..range.end]);
+            pieces.push(start..i);
+            i += newline;
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    pieces.push(start..range.end);
+    pieces.retain(|p| !p.is_empty());
+    pieces
+}
+
+#[cfg(test)]
+mod test {
+    use super::SemanticKind::*;
+    use super::{classify, encode_lsp, SemanticKind};
+    use lex::Lexer;
+    use line_index::LineIndex;
+    use options::{Dialect, LexerOptions};
+
+    fn kinds<'c>(code: &'c str, options: &LexerOptions) -> Vec<(&'c str, SemanticKind)> {
+        let options = LexerOptions {
+            recover: true,
+            ..*options
+        };
+        let tokens = Lexer::with_options(code, &options).lex();
+        classify(&tokens, code, &options)
+            .into_iter()
+            .map(|t| (&code[t.range], t.kind))
+            .collect()
+    }
+
+    fn kinds_of(code: &str) -> Vec<(&str, SemanticKind)> {
+        kinds(code, &LexerOptions::default())
+    }
+
+    #[test]
+    fn the_legend_should_stay_put() {
+        let names: Vec<&str> = SemanticKind::ALL.iter().map(|k| k.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "keyword",
+                "variable",
+                "property",
+                "function",
+                "parameter",
+                "number",
+                "string",
+                "stringEscape",
+                "regexp",
+                "interpolation",
+                "comment",
+                "operator",
+                "tag",
+                "attribute",
+            ]
+        );
+        assert_eq!(Comment.index(), 10);
+    }
+
+    #[test]
+    fn words_should_be_told_apart_by_what_they_are_and_where_they_sit() {
+        assert_eq!(
+            kinds_of("square = (x, y) -> x * y"),
+            vec![
+                ("square", Function),
+                ("x", Parameter),
+                ("y", Parameter),
+                ("-", Operator),
+                (">", Operator),
+                ("x", Variable),
+                ("y", Variable),
+            ]
+        );
+        assert_eq!(
+            kinds_of("if a.b then @c + d: e(1)"),
+            vec![
+                ("if", Keyword),
+                ("a", Variable),
+                ("b", Property),
+                ("then", Keyword),
+                ("c", Property),
+                ("+", Operator),
+                ("d", Property),
+                ("e", Function),
+                ("1", Number),
+            ]
+        );
+        // Which words are keywords is up to the options.
+        assert_eq!(kinds_of("await yes")[0].1, Keyword);
+        assert_eq!(kinds_of("await yes")[1].1, Keyword);
+        let cs1 = LexerOptions {
+            dialect: Dialect::CoffeeScript1,
+            word_booleans: false,
+            ..LexerOptions::default()
+        };
+        assert_eq!(kinds("await yes", &cs1)[0].1, Variable);
+        assert_eq!(kinds("await yes", &cs1)[1].1, Variable);
+    }
+
+    #[test]
+    fn strings_should_have_their_escapes_and_interpolations_picked_out() {
+        assert_eq!(
+            kinds_of("\"a\\tb #{c + 1}\" # d"),
+            vec![
+                ("\"", String),
+                ("a", String),
+                ("\\t", StringEscape),
+                ("b ", String),
+                ("#{", InterpolationPunctuation),
+                ("c", Variable),
+                ("+", Operator),
+                ("1", Number),
+                ("}", InterpolationPunctuation),
+                ("\"", String),
+                ("# d", Comment),
+            ]
+        );
+        assert_eq!(
+            kinds_of("/a+/g + <b c='d'>{e}</b>"),
+            vec![
+                ("/a+/g", Regex),
+                ("+", Operator),
+                ("<", Tag),
+                ("b", Tag),
+                ("c", Attribute),
+                ("=", Operator),
+                ("'d'", String),
+                (">", Tag),
+                ("{", InterpolationPunctuation),
+                ("e", Variable),
+                ("}", InterpolationPunctuation),
+                ("</", Tag),
+                ("b", Tag),
+                (">", Tag),
+            ]
+        );
+    }
+
+    #[test]
+    fn lsp_encoding_should_be_relative_in_utf16_and_one_line_at_a_time() {
+        let code = "a + \"😀\" + b\n###\nx\n###\n  c";
+        let tokens = Lexer::new(code).lex();
+        let semantic = classify(&tokens, code, &LexerOptions::default());
+        let data = encode_lsp(&semantic, &LineIndex::new(code));
+        let string = String.index();
+        let variable = Variable.index();
+        let operator = Operator.index();
+        let comment = Comment.index();
+        assert_eq!(
+            data,
+            vec![
+                0, 0, 1, variable, 0, // a
+                0, 2, 1, operator, 0, // +
+                0, 2, 1, string, 0, // "
+                0, 1, 2, string, 0, // the emoji, two code units
+                0, 2, 1, string, 0, // "
+                0, 2, 1, operator, 0, // +
+                0, 2, 1, variable, 0, // b
+                1, 0, 3, comment, 0, // ###, then the herecomment a line at a time
+                1, 0, 1, comment, 0, 1, 0, 3, comment, 0, 1, 2, 1, variable, 0, // c
+            ]
+        );
+    }
+}
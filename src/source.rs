@@ -0,0 +1,199 @@
+use chomp::{FileId, Position, Span};
+use lex::{Lexer, SourceCodeProvider};
+use line_index::{line_starts, LineIndex};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Whether a file by this name is Literate CoffeeScript. Everything that picks literate mode from a file name goes
+//   through here, so they all agree.
+pub fn is_literate_name(name: &str) -> bool {
+    name.ends_with(".litcoffee")
+}
+
+// Owned source text for tools that lex a whole project. Each file gets a FileId when it's added to the SourceMap,
+//   and every span lexed out of it carries that id, so any token can be traced back to path:line:col without the
+//   caller keeping track of which string it came from.
+
+pub struct SourceFile {
+    id: FileId,
+    name: String,
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn id(&self) -> FileId {
+        self.id
+    }
+
+    // Usually the path it was loaded from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line_index<'s>(&'s self) -> LineIndex<'s> {
+        LineIndex::from_line_starts(&self.text, &self.line_starts, 8)
+    }
+
+    pub fn is_literate(&self) -> bool {
+        is_literate_name(&self.name)
+    }
+
+    // A lexer over the file's text that stamps this file's id on every span. .litcoffee files get literate mode.
+    pub fn lexer<'s>(&'s self) -> Lexer<'s> {
+        let mut lexer = if self.is_literate() {
+            Lexer::literate(&self.text)
+        } else {
+            Lexer::new(&self.text)
+        };
+        lexer.set_file(self.id);
+        lexer
+    }
+
+    pub fn location<'s>(&'s self, position: Position) -> Location<'s> {
+        Location {
+            name: &self.name,
+            line_no: position.line_no,
+            col_no: position.col_no,
+        }
+    }
+}
+
+impl SourceCodeProvider for SourceFile {
+    fn get_source_code(&self) -> &str {
+        &self.text
+    }
+}
+
+// Where a span starts, for messages. Displays as path:line:col with a 1-based column, the way editors and
+//   terminals expect to be able to click on it (Position::col_no itself is 0-based).
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Location<'m> {
+    pub name: &'m str,
+    pub line_no: usize,
+    pub col_no: usize,
+}
+
+impl<'m> fmt::Display for Location<'m> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.line_no, self.col_no + 1)
+    }
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: vec![] }
+    }
+
+    pub fn add(&mut self, name: &str, text: String) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        self.files.push(SourceFile {
+            id,
+            name: name.to_string(),
+            line_starts: line_starts(&text),
+            text,
+        });
+        id
+    }
+
+    // Reads the file at path and adds it, named by its path.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FileId> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Ok(self.add(&path.to_string_lossy(), text))
+    }
+
+    pub fn file(&self, id: FileId) -> Option<&SourceFile> {
+        self.files.get(id.0 as usize)
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    // The text a span covers, in whichever file it came from. None for anonymous spans.
+    pub fn text(&self, span: &Span) -> Option<&str> {
+        self.file(span.file)
+            .map(|f| &f.text[span.start_pos.index..span.end_pos.index])
+    }
+
+    pub fn location<'s>(&'s self, span: &Span) -> Option<Location<'s>> {
+        self.file(span.file).map(|f| f.location(span.start_pos))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SourceMap;
+    use chomp::FileId;
+    use lex::TokenTag::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn tokens_from_any_file_should_resolve_to_name_line_col() {
+        let mut map = SourceMap::new();
+        let a = map.add("src/a.coffee", "40 + 2\n".to_string());
+        let b = map.add("src/b.coffee", "x\n  yy\n".to_string());
+        assert_ne!(a, b);
+
+        let b_tokens = map.file(b).unwrap().lexer().lex();
+        let yy = b_tokens
+            .iter()
+            .find(|t| t.tag == Word && t.span.start_pos.line_no == 2)
+            .unwrap();
+        assert_eq!(yy.span.file, b);
+        assert_eq!(map.text(&yy.span), Some("yy"));
+        assert_eq!(
+            map.location(&yy.span).unwrap().to_string(),
+            "src/b.coffee:2:3"
+        );
+
+        let a_tokens = map.file(a).unwrap().lexer().lex();
+        assert!(a_tokens.iter().all(|t| t.span.file == a));
+        assert_eq!(map.text(&a_tokens[0].span), Some("40"));
+    }
+
+    #[test]
+    fn anonymous_spans_should_not_resolve() {
+        let map = SourceMap::new();
+        let tokens = ::lex::Lexer::new("42").lex();
+        assert_eq!(tokens[0].span.file, FileId::ANONYMOUS);
+        assert_eq!(map.text(&tokens[0].span), None);
+        assert!(map.location(&tokens[0].span).is_none());
+    }
+
+    #[test]
+    fn litcoffee_files_should_be_lexed_as_literate() {
+        let mut map = SourceMap::new();
+        let id = map.add("docs.litcoffee", "Prose here.\n\n    a".to_string());
+        let tokens = map.file(id).unwrap().lexer().lex();
+        assert_eq!(tokens[0].tag, Comment);
+        assert_eq!(tokens.last().unwrap().tag, Word);
+    }
+
+    #[test]
+    fn load_should_read_the_file_and_keep_its_path() {
+        let path = env::temp_dir().join(format!("awarecs-source-{}.coffee", std::process::id()));
+        fs::write(&path, "a\r\nb").unwrap();
+        let mut map = SourceMap::new();
+        let id = map.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let file = map.file(id).unwrap();
+        assert_eq!(file.name(), path.to_string_lossy());
+        assert_eq!(file.line_index().line_count(), 2);
+        assert!(map.load(&path).is_err());
+    }
+}
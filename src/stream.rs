@@ -0,0 +1,157 @@
+use chomp::Span;
+use lex::{LexError, Lexer, SourceCodeProvider, Token};
+use std::ops::Index;
+use std::sync::Arc;
+
+// Tokens that own their source, for caching and indexing. Lexer and friends borrow the code, which is what you
+//   want while lexing but gets in the way once the tokens have to outlive the buffer they came from or cross a
+//   thread. A TokenStream holds the source as an Arc<str> next to the tokens, is Send + Sync, and clones in O(1)
+//   (both halves are reference counted), so handing one to another thread or keeping it in a long-lived map is free.
+
+#[derive(Debug, Clone)]
+pub struct TokenStream {
+    source: Arc<str>,
+    tokens: Arc<[Token]>,
+}
+
+impl TokenStream {
+    pub fn lex<S: Into<Arc<str>>>(source: S) -> Result<TokenStream, LexError> {
+        let source = source.into();
+        let tokens = Lexer::new(&source).try_lex()?;
+        Ok(TokenStream::from_tokens(source, tokens))
+    }
+
+    pub fn lex_literate<S: Into<Arc<str>>>(source: S) -> Result<TokenStream, LexError> {
+        let source = source.into();
+        let tokens = Lexer::literate(&source).try_lex()?;
+        Ok(TokenStream::from_tokens(source, tokens))
+    }
+
+    // tokens have to have been lexed out of source.
+    pub fn from_tokens(source: Arc<str>, tokens: Vec<Token>) -> TokenStream {
+        TokenStream {
+            source,
+            tokens: tokens.into(),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Another handle on the same source text, for keeping it alive without the tokens.
+    pub fn shared_source(&self) -> Arc<str> {
+        self.source.clone()
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // What Token::text gives: "[Tag text]".
+    pub fn text(&self, i: usize) -> String {
+        self.tokens[i].text(&self.source())
+    }
+
+    pub fn slice(&self, token: &Token) -> &str {
+        self.slice_span(&token.span)
+    }
+
+    pub fn slice_span(&self, span: &Span) -> &str {
+        &self.source[span.range()]
+    }
+
+    // Each token along with its text.
+    pub fn iter<'s>(&'s self) -> impl Iterator<Item = (Token, &'s str)> + 's {
+        self.tokens.iter().map(move |t| (*t, self.slice(t)))
+    }
+}
+
+impl Index<usize> for TokenStream {
+    type Output = Token;
+
+    fn index(&self, i: usize) -> &Token {
+        &self.tokens[i]
+    }
+}
+
+impl SourceCodeProvider for TokenStream {
+    fn get_source_code(&self) -> &str {
+        &self.source
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TokenStream;
+    use diff::diff_tokens;
+    use lex::Lexer;
+    use lex::TokenTag::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_and_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn should_be_send_and_sync() {
+        assert_send_and_sync::<TokenStream>();
+    }
+
+    #[test]
+    fn should_give_the_same_text_as_the_borrowed_tokens() {
+        let code = "40 + \"two #{x}\" # done";
+        let stream = TokenStream::lex(code).unwrap();
+        let tokens = Lexer::new(code).lex();
+        assert_eq!(stream.tokens(), &tokens[..]);
+        for (i, t) in tokens.iter().enumerate() {
+            assert_eq!(stream.text(i), t.text(&code));
+        }
+        assert_eq!(stream.slice(&stream[7]), "x");
+        assert_eq!(
+            stream.iter().map(|(_, text)| text).collect::<String>(),
+            code
+        );
+        assert!(diff_tokens(&["[Number 40]"], &stream.tokens()[..1], &stream).is_match());
+    }
+
+    #[test]
+    fn should_outlive_the_string_it_was_lexed_from_and_cross_threads() {
+        let stream = {
+            let code = String::from("a\n  b");
+            TokenStream::lex(code).unwrap()
+        };
+        let shared = stream.clone();
+        let words = thread::spawn(move || {
+            shared
+                .iter()
+                .filter(|(t, _)| t.tag == Word)
+                .map(|(_, text)| text.to_string())
+                .collect::<Vec<_>>()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(words, vec!["a", "b"]);
+        assert!(Arc::ptr_eq(
+            &stream.shared_source(),
+            &stream.clone().shared_source()
+        ));
+    }
+
+    #[test]
+    fn lex_errors_should_come_back_as_errors() {
+        assert!(TokenStream::lex("a ? b").is_err());
+        assert_eq!(
+            TokenStream::lex_literate("Prose\n\n    a").unwrap()[0].tag,
+            Comment
+        );
+        assert!(TokenStream::lex("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,571 @@
+use diff::escape;
+use lex::TokenTag::*;
+use lex::{line_indentation, Lexer, LexerState, Token, TokenTag};
+use options::{LexerOptions, WordKind};
+
+// Ways of writing a token list out, for the command line and for comparing with other tools.
+//
+// Text is one [Tag text] per line, escaped the way the diff module does it, which is exactly what token_diff
+//   takes as its expected file. Json is an array with one object per token. Coffee is what the reference
+//   compiler's coffee --tokens prints: its tag names, no trivia, and the INDENT, OUTDENT and TERMINATOR tokens its
+//   lexer would make out of our NewlineAndIndent. It's only as close as this lexer can get: strings come out the
+//   way CoffeeScript 1 splits them around interpolations, and a char this lexer can't make anything of (only there
+//   with LexerOptions::recover on) comes out as itself, which is what the reference does for punctuation.
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TokenFormat {
+    Text,
+    Coffee,
+    Json,
+}
+
+impl TokenFormat {
+    pub fn from_name(name: &str) -> Option<TokenFormat> {
+        match name {
+            "text" => Some(TokenFormat::Text),
+            "coffee" => Some(TokenFormat::Coffee),
+            "json" => Some(TokenFormat::Json),
+            _ => None,
+        }
+    }
+
+    // tokens are what the lexer made of code with options.
+    pub fn render(&self, tokens: &[Token], code: &str, options: &LexerOptions) -> String {
+        match *self {
+            TokenFormat::Text => to_text(tokens, code),
+            TokenFormat::Coffee => to_coffee(tokens, code, options),
+            TokenFormat::Json => to_json(tokens, code),
+        }
+    }
+}
+
+pub fn to_text(tokens: &[Token], code: &str) -> String {
+    let mut out = String::new();
+    for token in tokens {
+        out.push_str(&escape(&token.text(&code)));
+        out.push('\n');
+    }
+    out
+}
+
+// Lines and columns are the chomper's (1-based line, 0-based column in chars); start and end are byte offsets.
+pub fn to_json(tokens: &[Token], code: &str) -> String {
+    let mut out = String::from("[");
+    for (i, token) in tokens.iter().enumerate() {
+        out.push_str(if i == 0 { "\n  " } else { ",\n  " });
+        out.push_str("{\"tag\": ");
+        push_json_string(&mut out, &format!("{:?}", token.tag));
+        out.push_str(", \"text\": ");
+        push_json_string(&mut out, &code[token.span.range()]);
+        out.push_str(&format!(
+            ", \"line\": {}, \"col\": {}, \"start\": {}, \"end\": {}}}",
+            token.span.start_pos.line_no,
+            token.span.start_pos.col_no,
+            token.span.start_pos.index,
+            token.span.end_pos.index
+        ));
+    }
+    out.push_str(if tokens.is_empty() { "]\n" } else { "\n]\n" });
+    out
+}
+
+fn push_json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// All on one line, the way coffee --tokens prints them.
+pub fn to_coffee(tokens: &[Token], code: &str, options: &LexerOptions) -> String {
+    let mut coffee = CoffeeTokens::new(code, options);
+    coffee.add_all(tokens, 0);
+    coffee.close_indentation();
+    let mut out = coffee
+        .out
+        .iter()
+        .map(|(tag, value)| format!("[{} {}]", tag, escape(value)))
+        .collect::<Vec<String>>()
+        .join(" ");
+    out.push('\n');
+    out
+}
+
+type CoffeeToken = (String, String);
+
+// A string (or heregex) that's still open: the text since the last interpolation, and what's been put together so
+//   far. Inside an interpolation, code collects what comes out of it.
+struct OpenString {
+    start: usize,
+    is_regex: bool,
+    text: String,
+    pieces: Vec<StringPiece>,
+    code: Option<Vec<CoffeeToken>>,
+}
+
+enum StringPiece {
+    Text(String),
+    Code(Vec<CoffeeToken>),
+}
+
+// Turns our tokens into the reference's, keeping the indentation the way its lineToken and outdentToken do.
+struct CoffeeTokens<'c> {
+    code: &'c str,
+    options: LexerOptions,
+    state: LexerState,
+    out: Vec<CoffeeToken>,
+    strings: Vec<OpenString>,
+    indent: usize,
+    base_indent: usize,
+    // Indentation after an unfinished line (a + at the end, say), which doesn't open a block.
+    indebt: usize,
+    // What an outdent to somewhere between two levels still owes.
+    outdebt: usize,
+    indents: Vec<usize>,
+}
+
+impl<'c> CoffeeTokens<'c> {
+    fn new(code: &'c str, options: &LexerOptions) -> CoffeeTokens<'c> {
+        CoffeeTokens {
+            code,
+            options: *options,
+            state: if options.literate {
+                LexerState::start_literate(code)
+            } else {
+                LexerState::start(code)
+            },
+            out: vec![],
+            strings: vec![],
+            indent: 0,
+            base_indent: 0,
+            indebt: 0,
+            outdebt: 0,
+            indents: vec![],
+        }
+    }
+
+    // tokens were lexed out of code[offset..]. Only the top-level ones (offset 0) move the state along.
+    fn add_all(&mut self, tokens: &[Token], offset: usize) {
+        for token in tokens {
+            if offset == 0 {
+                self.state.advance(token, self.code);
+            }
+            let start = token.span.start_pos.index + offset;
+            let text = &self.code[start..token.span.end_pos.index + offset];
+            self.add(token, start, text);
+        }
+    }
+
+    fn add(&mut self, token: &Token, start: usize, text: &'c str) {
+        match token.tag {
+            Whitespace | Comment | Shebang => {}
+            NewlineAndIndent => {
+                if let Some(indentation) = line_indentation(token, self.code, self.state.literate) {
+                    self.line(indentation.chars().count());
+                }
+            }
+            Herecomment => {
+                let body = token.herecomment_body(&self.code).unwrap();
+                self.emit("HERECOMMENT", &body);
+            }
+            Number => self.emit("NUMBER", text),
+            Word => {
+                let (tag, value) = self.word(text);
+                self.emit(tag, value);
+            }
+            Operator => {
+                let tag = match text {
+                    "/" => "MATH",
+                    "<" | ">" | "<=" | ">=" => "COMPARE",
+                    "<<" | ">>" | ">>>" => "SHIFT",
+                    _ => text,
+                };
+                self.emit(tag, text);
+            }
+            Regex => self.emit("REGEX", text),
+            OpenQuote | OpenRegex => self.strings.push(OpenString {
+                start,
+                is_regex: token.tag == OpenRegex,
+                text: String::new(),
+                pieces: vec![],
+                code: None,
+            }),
+            StringFragment | RegexFragment => self.strings.last_mut().unwrap().text.push_str(text),
+            OpenInterpolation => {
+                let string = self.strings.last_mut().unwrap();
+                let text = string.text.split_off(0);
+                string.pieces.push(StringPiece::Text(text));
+                string.code = Some(vec![]);
+            }
+            InterpolatedCode => {
+                let inner_options = LexerOptions {
+                    literate: false,
+                    recover: true,
+                    ..self.options
+                };
+                if let Ok(inner) = Lexer::with_options(text, &inner_options).try_lex() {
+                    self.add_all(&inner, start);
+                }
+            }
+            CloseInterpolation => {
+                let string = self.strings.last_mut().unwrap();
+                let code = string.code.take().unwrap_or_default();
+                string.pieces.push(StringPiece::Code(code));
+            }
+            CloseQuote | CloseRegex => {
+                let string = self.strings.pop().unwrap();
+                let end = start + text.len();
+                self.close_string(string, end);
+            }
+            TokenTag::Error => self.emit(text, text),
+            tag => {
+                let name = upper_snake_case(&format!("{:?}", tag));
+                self.emit(&name, text);
+            }
+        }
+    }
+
+    fn word(&self, word: &'c str) -> (&'c str, &'c str) {
+        match word {
+            "and" => ("LOGIC", "&&"),
+            "or" => ("LOGIC", "||"),
+            "is" => ("COMPARE", "=="),
+            "isnt" => ("COMPARE", "!="),
+            "not" => ("UNARY", "!"),
+            "new" | "typeof" | "delete" | "do" => ("UNARY", word),
+            "in" | "of" | "instanceof" => ("RELATION", word),
+            _ => match self.options.word_kind(word) {
+                WordKind::Boolean => match word {
+                    "yes" | "on" => ("BOOL", "true"),
+                    "no" | "off" => ("BOOL", "false"),
+                    _ => ("BOOL", word),
+                },
+                WordKind::Keyword => (keyword_tag(word), word),
+                WordKind::Reserved | WordKind::Identifier => ("IDENTIFIER", word),
+            },
+        }
+    }
+
+    // A string with no interpolations is one STRING. One with them is ( "text" + (code) + "text" ), always starting
+    //   with a string. A heregex is one REGEX, whatever's in it.
+    fn close_string(&mut self, mut string: OpenString, end: usize) {
+        if string.is_regex {
+            let text = &self.code[string.start..end];
+            self.emit("REGEX", text);
+            return;
+        }
+        let text = string.text.split_off(0);
+        string.pieces.push(StringPiece::Text(text));
+        if string.pieces.len() == 1 {
+            if let Some(StringPiece::Text(ref text)) = string.pieces.first() {
+                self.emit("STRING", &quote(text));
+            }
+            return;
+        }
+        self.emit("(", "(");
+        let mut first = true;
+        for piece in string.pieces {
+            match piece {
+                StringPiece::Text(ref text) if text.is_empty() && !first => continue,
+                StringPiece::Text(text) => {
+                    if !first {
+                        self.emit("+", "+");
+                    }
+                    self.emit("STRING", &quote(&text));
+                }
+                StringPiece::Code(code) => {
+                    self.emit("+", "+");
+                    self.emit("(", "(");
+                    for token in code {
+                        self.push(token);
+                    }
+                    self.emit(")", ")");
+                }
+            }
+            first = false;
+        }
+        self.emit(")", ")");
+    }
+
+    fn emit(&mut self, tag: &str, value: &str) {
+        self.push((tag.to_string(), value.to_string()));
+    }
+
+    // Into the interpolation we're in the middle of, if there is one.
+    fn push(&mut self, token: CoffeeToken) {
+        match self.strings.last_mut().and_then(|s| s.code.as_mut()) {
+            Some(code) => code.push(token),
+            None => self.out.push(token),
+        }
+    }
+
+    fn last_tag(&self) -> Option<&str> {
+        self.out.last().map(|t| t.0.as_str())
+    }
+
+    // The line so far can't end here, so a line break doesn't end it.
+    fn unfinished(&self) -> bool {
+        match self.last_tag() {
+            Some(tag) => UNFINISHED.contains(&tag),
+            None => false,
+        }
+    }
+
+    // A new line, indented size.
+    fn line(&mut self, size: usize) {
+        let no_newlines = self.unfinished();
+        if size == self.indent + self.indebt {
+            if !no_newlines {
+                self.newline();
+            }
+            return;
+        }
+        if size > self.indent {
+            if no_newlines {
+                self.indebt = size - self.indent;
+                return;
+            }
+            if self.out.is_empty() {
+                self.base_indent = size;
+                self.indent = size;
+                return;
+            }
+            let diff = size - self.indent + self.outdebt;
+            self.emit("INDENT", &diff.to_string());
+            self.indents.push(diff);
+            self.outdebt = 0;
+            self.indebt = 0;
+            self.indent = size;
+        } else {
+            // The reference calls going below the base indent an error. We've already lexed it, so just outdent.
+            self.indebt = 0;
+            let size = size.max(self.base_indent);
+            self.outdent(self.indent - size, no_newlines);
+        }
+    }
+
+    // The reference rewriter drops TERMINATORs at the start of the file (a leading comment, say), so none go there.
+    fn newline(&mut self) {
+        if self.last_tag().is_some_and(|tag| tag != "TERMINATOR") {
+            self.emit("TERMINATOR", "\n");
+        }
+    }
+
+    fn outdent(&mut self, move_out: usize, no_newlines: bool) {
+        let decreased = self.indent - move_out;
+        let mut move_out = move_out as isize;
+        let mut dented = false;
+        while move_out > 0 {
+            let last = match self.indents.last() {
+                None => break,
+                Some(&last) => last,
+            };
+            if last == self.outdebt {
+                move_out -= self.outdebt as isize;
+                self.outdebt = 0;
+            } else if last < self.outdebt {
+                self.outdebt -= last;
+                move_out -= last as isize;
+            } else {
+                let dent = self.indents.pop().unwrap() + self.outdebt;
+                self.outdebt = 0;
+                self.emit("OUTDENT", &move_out.to_string());
+                move_out -= dent as isize;
+                dented = true;
+            }
+        }
+        if dented && move_out < 0 {
+            self.outdebt = (-move_out) as usize;
+        }
+        if !no_newlines {
+            self.newline();
+        }
+        self.indent = decreased;
+    }
+
+    fn close_indentation(&mut self) {
+        let indent = self.indent - self.base_indent;
+        self.outdent(indent, false);
+    }
+}
+
+// text in double quotes, the way the reference prints a STRING. A " that isn't escaped already (in a heredoc, say)
+//   gets a backslash; the backslashes that are there stay as written.
+fn quote(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    let mut escaped = false;
+    for c in text.chars() {
+        if c == '"' && !escaped {
+            out.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+// Tags that leave a line unfinished, from the reference lexer.
+const UNFINISHED: &[&str] = &[
+    "\\",
+    ".",
+    "?.",
+    "?::",
+    "UNARY",
+    "MATH",
+    "UNARY_MATH",
+    "+",
+    "-",
+    "**",
+    "SHIFT",
+    "RELATION",
+    "COMPARE",
+    "LOGIC",
+    "THROW",
+    "EXTENDS",
+];
+
+fn keyword_tag(word: &str) -> &'static str {
+    match word {
+        "if" | "unless" => "IF",
+        "else" => "ELSE",
+        "then" => "THEN",
+        "for" => "FOR",
+        "while" | "until" => "WHILE",
+        "loop" => "LOOP",
+        "when" => "LEADING_WHEN",
+        "by" => "BY",
+        "return" => "RETURN",
+        "throw" => "THROW",
+        "break" | "continue" | "debugger" => "STATEMENT",
+        "switch" => "SWITCH",
+        "try" => "TRY",
+        "catch" => "CATCH",
+        "finally" => "FINALLY",
+        "class" => "CLASS",
+        "extends" => "EXTENDS",
+        "super" => "SUPER",
+        "this" => "THIS",
+        "null" => "NULL",
+        "undefined" => "UNDEFINED",
+        "yield" => "YIELD",
+        "await" => "AWAIT",
+        "import" => "IMPORT",
+        "export" => "EXPORT",
+        "default" => "DEFAULT",
+        _ => "IDENTIFIER",
+    }
+}
+
+// JsxTagName to JSX_TAG_NAME.
+fn upper_snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.push(c.to_ascii_uppercase());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_coffee, to_json, to_text, TokenFormat};
+    use diff::{diff_tokens, unescape};
+    use lex::Lexer;
+    use options::LexerOptions;
+    use std::fs;
+    use std::path::Path;
+
+    fn coffee(code: &str) -> String {
+        let options = LexerOptions {
+            recover: true,
+            ..LexerOptions::default()
+        };
+        to_coffee(&Lexer::with_options(code, &options).lex(), code, &options)
+    }
+
+    // The cases in unbuilt/cases that this lexer has every token for. Their .tokens files come from a reference
+    //   compiler patched to print which lineToken branch it took, so the tokens are the last line.
+    const GOLDEN: &[&str] = &[
+        "42.coffee",
+        "2.level.string.interpolation.coffee",
+        "branch1.1.coffee",
+        "branch1.2.coffee",
+        "branch2.coffee",
+        "branch3.1.coffee",
+        "branch3.2.coffee",
+        "branch3.3.coffee",
+        "branch3.4.coffee",
+        "branch5.coffee",
+        "bunch_of_newlines.coffee",
+    ];
+
+    #[test]
+    fn coffee_format_should_match_the_reference_compiler_on_the_cases() {
+        let cases = Path::new(env!("CARGO_MANIFEST_DIR")).join("unbuilt/cases");
+        for name in GOLDEN.iter() {
+            let source = fs::read_to_string(cases.join(name)).unwrap();
+            let expected = fs::read_to_string(cases.join(name).with_extension("tokens")).unwrap();
+            let expected = expected.lines().rfind(|l| l.starts_with('[')).unwrap();
+            assert_eq!(coffee(&source).trim_end(), expected, "in {}", name);
+        }
+    }
+
+    #[test]
+    fn coffee_format_should_outdent_in_steps_and_ignore_unfinished_lines() {
+        assert_eq!(
+            coffee("a\n  b\n    c +\n      d\nf"),
+            "[IDENTIFIER a] [INDENT 2] [IDENTIFIER b] [INDENT 2] [IDENTIFIER c] [+ +] [IDENTIFIER d] \
+             [OUTDENT 4] [OUTDENT 2] [TERMINATOR \\n] [IDENTIFIER f] [TERMINATOR \\n]\n"
+        );
+        assert_eq!(
+            coffee("if yes and x isnt 010 # c\n  \"#{y}\""),
+            "[IF if] [BOOL true] [LOGIC &&] [IDENTIFIER x] [COMPARE !=] [NUMBER 010] [INDENT 2] [( (] \
+             [STRING \"\"] [+ +] [( (] [IDENTIFIER y] [) )] [) )] [OUTDENT 2] [TERMINATOR \\n]\n"
+        );
+        // A " in a heredoc is escaped for the quotes around it; an escaped one already is.
+        assert_eq!(
+            coffee("\"\"\"a\"b\\\"c\"\"\""),
+            "[STRING \"a\\\\\"b\\\\\"c\"] [TERMINATOR \\n]\n"
+        );
+    }
+
+    #[test]
+    fn text_format_should_be_what_token_diff_reads() {
+        let code = "a + \"b\n\"";
+        let tokens = Lexer::new(code).lex();
+        let text = to_text(&tokens, code);
+        assert_eq!(text.lines().nth(5), Some("[StringFragment b]"));
+        assert_eq!(text.lines().nth(6), Some("[StringFragment \\n]"));
+        let expected: Vec<String> = text.lines().map(unescape).collect();
+        let expected: Vec<&str> = expected.iter().map(|l| l.as_str()).collect();
+        assert!(diff_tokens(&expected, &tokens, &code).is_match());
+    }
+
+    #[test]
+    fn json_format_should_escape_and_place_every_token() {
+        let code = "\"\\\"\"";
+        let json = to_json(&Lexer::new(code).lex(), code);
+        assert_eq!(
+            json,
+            "[\n  {\"tag\": \"OpenQuote\", \"text\": \"\\\"\", \"line\": 1, \"col\": 0, \"start\": 0, \"end\": 1},\n  \
+             {\"tag\": \"StringFragment\", \"text\": \"\\\\\\\"\", \"line\": 1, \"col\": 1, \"start\": 1, \"end\": 3},\n  \
+             {\"tag\": \"CloseQuote\", \"text\": \"\\\"\", \"line\": 1, \"col\": 3, \"start\": 3, \"end\": 4}\n]\n"
+        );
+        assert_eq!(to_json(&[], ""), "[]\n");
+        assert_eq!(TokenFormat::from_name("json"), Some(TokenFormat::Json));
+        assert_eq!(TokenFormat::from_name("yaml"), None);
+    }
+}
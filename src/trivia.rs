@@ -0,0 +1,202 @@
+use chomp::Position;
+use lex::{Lexer, SourceCodeProvider, Token, TokenTag};
+
+// An alternative view of the lexer's output for tools that rewrite source (the formatter, refactorings). Instead of
+//   Whitespace, Comment, Herecomment and NewlineAndIndent sitting in the stream next to everything else, each
+//   significant token carries them along as leading and trailing trivia.
+//
+// The rules, same as most trivia-preserving lexers:
+//   * Trailing trivia is the whitespace and comments that follow a token on its own line. It stops at a newline.
+//   * Everything else -- newlines, indentation, whole-line comments -- is leading trivia of the next token.
+//   * Whatever trivia comes after the last significant token lives in TriviaStream::eof_trivia.
+//
+// The guarantee: concatenating leading trivia, token text and trailing trivia for every token, then eof_trivia,
+//   reproduces the source byte for byte. The tests below hold us to that.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TriviaToken {
+    pub leading: Vec<Token>,
+    pub token: Token,
+    pub trailing: Vec<Token>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TriviaStream {
+    pub tokens: Vec<TriviaToken>,
+    pub eof_trivia: Vec<Token>,
+}
+
+impl TriviaStream {
+    pub fn from_tokens(tokens: Vec<Token>) -> TriviaStream {
+        let mut result: Vec<TriviaToken> = vec![];
+        let mut pending: Vec<Token> = vec![];
+        // True while we're still on the same line as the last significant token, i.e. trivia is trailing.
+        let mut trailing = false;
+
+        for token in tokens {
+            if !token.tag.is_trivia() {
+                result.push(TriviaToken {
+                    leading: pending,
+                    token,
+                    trailing: vec![],
+                });
+                pending = vec![];
+                trailing = true;
+                continue;
+            }
+
+            if token.tag == TokenTag::NewlineAndIndent {
+                trailing = false;
+            }
+
+            if trailing {
+                result.last_mut().unwrap().trailing.push(token);
+            } else {
+                pending.push(token);
+            }
+        }
+
+        TriviaStream {
+            tokens: result,
+            eof_trivia: pending,
+        }
+    }
+
+    // Every token in source order, trivia included. This is exactly what Lexer::lex returned.
+    pub fn flatten(&self) -> Vec<Token> {
+        let mut result = vec![];
+        for t in self.tokens.iter() {
+            result.extend(t.leading.iter().cloned());
+            result.push(t.token);
+            result.extend(t.trailing.iter().cloned());
+        }
+        result.extend(self.eof_trivia.iter().cloned());
+        result
+    }
+
+    pub fn to_source<TSource>(&self, source: &TSource) -> String
+    where
+        TSource: SourceCodeProvider,
+    {
+        let code = source.get_source_code();
+        let mut result = String::with_capacity(code.len());
+        // A byte order mark is the only thing that isn't in any token.
+        result.push_str(&code[..Position::start_of(code).index]);
+        for token in self.flatten() {
+            result.push_str(&code[token.span.start_pos.index..token.span.end_pos.index]);
+        }
+        result
+    }
+}
+
+impl<'li> Lexer<'li> {
+    pub fn lex_with_trivia(&mut self) -> TriviaStream {
+        TriviaStream::from_tokens(self.lex())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TriviaStream;
+    use lex::Lexer;
+    use lex::TokenTag::*;
+
+    fn assert_round_trips(code: &str) -> TriviaStream {
+        let stream = Lexer::new(code).lex_with_trivia();
+        assert_eq!(stream.to_source(&code), code);
+        for t in stream.tokens.iter() {
+            assert!(!t.token.tag.is_trivia());
+            assert!(t
+                .leading
+                .iter()
+                .chain(t.trailing.iter())
+                .all(|l| l.tag.is_trivia()));
+        }
+        stream
+    }
+
+    #[test]
+    fn should_attach_same_line_trivia_as_trailing_and_the_rest_as_leading() {
+        let code = "40 # the answer, almost\n  # on its own line\n  2 ";
+        let stream = assert_round_trips(code);
+
+        assert_eq!(stream.tokens.len(), 2);
+        let first = &stream.tokens[0];
+        assert_eq!(first.token.tag, Number);
+        assert!(first.leading.is_empty());
+        assert_eq!(
+            first.trailing.iter().map(|t| t.tag).collect::<Vec<_>>(),
+            vec![Whitespace, Comment]
+        );
+
+        let second = &stream.tokens[1];
+        assert_eq!(
+            second.leading.iter().map(|t| t.tag).collect::<Vec<_>>(),
+            vec![NewlineAndIndent, Comment, NewlineAndIndent]
+        );
+        assert_eq!(
+            second.trailing.iter().map(|t| t.tag).collect::<Vec<_>>(),
+            vec![Whitespace]
+        );
+        assert!(stream.eof_trivia.is_empty());
+    }
+
+    #[test]
+    fn should_keep_trivia_after_the_last_token_as_eof_trivia() {
+        let code = "42\n\n# done\n";
+        let stream = assert_round_trips(code);
+        assert_eq!(stream.tokens.len(), 1);
+        assert_eq!(
+            stream.eof_trivia.iter().map(|t| t.tag).collect::<Vec<_>>(),
+            vec![
+                NewlineAndIndent,
+                NewlineAndIndent,
+                Comment,
+                NewlineAndIndent
+            ]
+        );
+    }
+
+    #[test]
+    fn a_source_with_nothing_but_trivia_should_still_round_trip() {
+        let stream = assert_round_trips("   \n### all\ncomment ###\n");
+        assert!(stream.tokens.is_empty());
+        assert_round_trips("");
+    }
+
+    #[test]
+    fn flatten_should_give_back_exactly_what_lex_returned() {
+        let code = "a + \"b #{c} d\" # e\n  f";
+        let tokens = Lexer::new(code).lex();
+        let stream = TriviaStream::from_tokens(tokens.clone());
+        assert_eq!(stream.flatten(), tokens);
+    }
+
+    #[test]
+    fn strings_and_interpolations_should_round_trip() {
+        assert_round_trips(r#""The string is #{"The #{40 + 2}nd string".length} characters long""#);
+        assert_round_trips(r#"x "a \" b" ### c ### y"#);
+    }
+
+    #[test]
+    fn crlf_bom_and_shebang_should_round_trip() {
+        let stream = assert_round_trips("\u{feff}#!/usr/bin/env coffee\r\na \"b\r\nc\"\r  d\r\n");
+        assert_eq!(stream.tokens[0].leading[0].tag, Shebang);
+        assert_round_trips("\u{feff}");
+    }
+
+    #[test]
+    fn golden_cases_should_round_trip_byte_for_byte() {
+        assert_round_trips(include_str!("../unbuilt/cases/42.coffee"));
+        assert_round_trips(include_str!(
+            "../unbuilt/cases/2.level.string.interpolation.coffee"
+        ));
+        assert_round_trips(include_str!("../unbuilt/cases/branch5.coffee"));
+        assert_round_trips(include_str!("../unbuilt/cases/bunch_of_newlines.coffee"));
+        assert_round_trips(include_str!("../unbuilt/cases/kept_on_indenting.coffee"));
+        assert_round_trips(include_str!(
+            "../unbuilt/cases/covers_all_flush_baseindent_branches_of_lineToken.case"
+        ));
+        assert_round_trips(include_str!("../unbuilt/charlie-to-parse.txt"));
+    }
+}